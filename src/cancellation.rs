@@ -0,0 +1,45 @@
+use std::sync::atomic::{ AtomicBool, Ordering };
+
+use crate::custom_error::{ CustomError, CustomResult };
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+// The first SIGINT/SIGTERM asks the run to stop at the next table boundary,
+// a second one exits right away
+#[cfg(unix)]
+pub fn install_signal_handlers() {
+    extern "C" fn handle_signal(_signal: libc::c_int) {
+        if CANCELLED.swap(true, Ordering::SeqCst) {
+            unsafe {
+                libc::_exit(130);
+            }
+        }
+
+        // Only async-signal-safe calls are allowed here, so no logger
+        let message = b"Stopping after the current table, signal again to exit immediately\n";
+        unsafe {
+            libc::write(libc::STDERR_FILENO, message.as_ptr().cast(), message.len());
+        }
+    }
+
+    let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install_signal_handlers() {}
+
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+pub fn check_cancelled() -> CustomResult<()> {
+    if is_cancelled() {
+        Err(CustomError::Cancelled)
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,291 @@
+use std::{ collections::BTreeMap, fs, path::Path, sync::Mutex };
+
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+
+use crate::{
+    config::{ Config, ConflictMode },
+    custom_error::{ CustomError, CustomResult },
+    files::write_bytes_atomically,
+    logger::LoggerTrait,
+    traits::TableQuery,
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TableCheckpoint {
+    pub category: String,
+    pub table: String,
+    pub rows: usize,
+    pub file_path: Option<String>,
+    pub extracted: bool,
+    pub loaded: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Checkpoint {
+    // Config values that decide which rows are copied, a resumed run must match them
+    pub scope: Value,
+    pub tables: BTreeMap<String, TableCheckpoint>,
+}
+
+// Tracks which tables finished extracting and loading in checkpoint.json in the target folder
+#[derive(Debug)]
+pub struct CheckpointStore {
+    folder_path: String,
+    checkpoint: Mutex<Checkpoint>,
+}
+
+impl LoggerTrait for CheckpointStore {}
+impl CheckpointStore {
+    pub fn open(config: &Config, resume: bool) -> CustomResult<Self> {
+        let logger = crate::logger::Logger::new();
+        let folder_path = config.target_path.path.clone();
+        let scope = get_scope(config);
+        let file_path = get_file_path(&folder_path);
+
+        let checkpoint = if resume && Path::new(&file_path).exists() {
+            let checkpoint = read_checkpoint(&file_path)?;
+            if checkpoint.scope != scope {
+                logger.error("Checkpoint was written for a different config scope");
+                return Err(CustomError::CheckpointScopeMismatch);
+            }
+            logger.warn(format!("Resuming run from {}", file_path).as_str());
+            checkpoint
+        } else {
+            if resume {
+                logger.warn("No checkpoint found, starting from scratch");
+            }
+            Checkpoint { scope, tables: BTreeMap::new() }
+        };
+
+        Ok(Self { folder_path, checkpoint: Mutex::new(checkpoint) })
+    }
+
+    // Returns the table from a previous run when its extract can be reused as is
+    pub fn get_extracted(
+        &self,
+        category: &str,
+        table: &str,
+        conflict_mode: ConflictMode
+    ) -> Option<TableQuery> {
+        let checkpoint = self.checkpoint.lock().unwrap();
+        let table_checkpoint = checkpoint.tables.get(&get_key(category, table))?;
+        if !table_checkpoint.extracted {
+            return None;
+        }
+
+        let query = match &table_checkpoint.file_path {
+            Some(file_path) => fs::read_to_string(file_path).ok()?,
+            None if table_checkpoint.rows == 0 => String::new(),
+            // Rows were loaded without SQL text, they have to be extracted again
+            None => {
+                return None;
+            }
+        };
+
+        self.get_logger().info(format!("Reusing extracted table {}", table).as_str());
+        Some(TableQuery {
+            table: table.to_string(),
+            query,
+            rows: table_checkpoint.rows,
+            conflict_mode,
+            data: None,
+        })
+    }
+
+    pub fn mark_extracted(&self, category: &str, table_query: &TableQuery) -> CustomResult<()> {
+        let file_path = if table_query.query.is_empty() {
+            None
+        } else {
+            let tables_folder = format!("{}/tables", self.folder_path);
+            fs::create_dir_all(&tables_folder).map_err(|source| {
+                CustomError::FolderCreationError { path: tables_folder.clone(), source }
+            })?;
+            let file_path = format!("{}/{}.sql", tables_folder, table_query.table);
+            write_bytes_atomically(&file_path, table_query.query.as_bytes())?;
+            Some(file_path)
+        };
+
+        let mut checkpoint = self.checkpoint.lock().unwrap();
+        checkpoint.tables.insert(get_key(category, &table_query.table), TableCheckpoint {
+            category: category.to_string(),
+            table: table_query.table.clone(),
+            rows: table_query.rows,
+            file_path,
+            extracted: true,
+            loaded: false,
+        });
+
+        self.persist(&checkpoint)
+    }
+
+    pub fn is_loaded(&self, category: &str, table: &str) -> bool {
+        let checkpoint = self.checkpoint.lock().unwrap();
+
+        match checkpoint.tables.get(&get_key(category, table)) {
+            Some(table_checkpoint) => table_checkpoint.loaded,
+            None => false,
+        }
+    }
+
+    pub fn mark_loaded(&self, category: &str, table: &str) -> CustomResult<()> {
+        let mut checkpoint = self.checkpoint.lock().unwrap();
+        let table_checkpoint = checkpoint.tables
+            .entry(get_key(category, table))
+            .or_insert_with(|| TableCheckpoint {
+                category: category.to_string(),
+                table: table.to_string(),
+                rows: 0,
+                file_path: None,
+                extracted: false,
+                loaded: false,
+            });
+        table_checkpoint.loaded = true;
+
+        self.persist(&checkpoint)
+    }
+
+    fn persist(&self, checkpoint: &Checkpoint) -> CustomResult<()> {
+        fs::create_dir_all(&self.folder_path).map_err(|source| CustomError::FolderCreationError {
+            path: self.folder_path.clone(),
+            source,
+        })?;
+        let file_path = get_file_path(&self.folder_path);
+        let content = serde_json
+            ::to_string_pretty(checkpoint)
+            .map_err(|source| CustomError::FileDataInsertionError {
+                path: file_path.clone(),
+                source: Box::new(source),
+            })?;
+
+        write_bytes_atomically(&file_path, content.as_bytes())
+    }
+}
+
+fn get_file_path(folder_path: &str) -> String {
+    format!("{}/checkpoint.json", folder_path)
+}
+
+fn get_key(category: &str, table: &str) -> String {
+    format!("{}/{}", category, table)
+}
+
+// Holds no credentials, so it can also go into the run report
+pub fn get_scope(config: &Config) -> Value {
+    let mut scope = json!({
+        "source": {
+            "host": config.source.host,
+            "port": config.source.port,
+            "database": config.source.database,
+        },
+        "redshift_db": {
+            "host": config.redshift_db.host,
+            "port": config.redshift_db.port,
+            "database": config.redshift_db.database,
+        },
+        "business": config.business,
+        "tables": config.tables,
+        "insert": config.insert,
+        // Decides whether a table is kept as SQL text or as rows
+        "load_method": config.load.method,
+    });
+    // Left out when off, so checkpoints of runs without sampling or delta still match
+    if config.sample.enabled {
+        scope["sample"] = json!(config.sample);
+    }
+    if config.delta.enabled {
+        scope["delta"] = json!(config.delta);
+    }
+
+    scope
+}
+
+fn read_checkpoint(file_path: &str) -> CustomResult<Checkpoint> {
+    let content = fs::read_to_string(file_path).map_err(|source| CustomError::CheckpointRead {
+        path: file_path.to_string(),
+        source: Box::new(source),
+    })?;
+
+    serde_json::from_str(&content).map_err(|source| CustomError::CheckpointRead {
+        path: file_path.to_string(),
+        source: Box::new(source),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::json;
+
+    use crate::config::{ get_test_config, ConflictMode };
+    use crate::custom_error::CustomError;
+
+    use super::{ get_file_path, get_scope, Checkpoint, CheckpointStore };
+
+    #[test]
+    fn scope_changes_with_the_rows_that_are_copied() {
+        let config = get_test_config();
+        let scope = get_scope(&config);
+
+        let mut changed = get_test_config();
+        changed.business.subject_id = Some(5);
+        assert_ne!(get_scope(&changed), scope);
+
+        let mut changed = get_test_config();
+        changed.tables.batch_tables.push("cb_batch_runs".to_string());
+        assert_ne!(get_scope(&changed), scope);
+
+        let mut changed = get_test_config();
+        changed.insert.conflict_mode = ConflictMode::Upsert;
+        assert_ne!(get_scope(&changed), scope);
+
+        let mut changed = get_test_config();
+        changed.source.database = "other".to_string();
+        assert_ne!(get_scope(&changed), scope);
+    }
+
+    #[test]
+    fn scope_leaves_out_credentials() {
+        let config = get_test_config();
+        let mut changed = get_test_config();
+        changed.source.password = "rotated".to_string();
+        changed.redshift_db.username = "someone".to_string();
+
+        assert_eq!(get_scope(&changed), get_scope(&config));
+        assert!(!get_scope(&config).to_string().contains("pass"));
+    }
+
+    #[test]
+    fn sample_and_delta_only_count_when_enabled() {
+        let config = get_test_config();
+        let mut changed = get_test_config();
+        changed.sample.seed = 9;
+        changed.delta.delete_missing = true;
+        assert_eq!(get_scope(&changed), get_scope(&config));
+
+        changed.sample.enabled = true;
+        assert_ne!(get_scope(&changed), get_scope(&config));
+        assert_eq!(get_scope(&changed)["sample"]["seed"], json!(9));
+    }
+
+    #[test]
+    fn resume_refuses_a_checkpoint_of_another_scope() {
+        let folder = std::env::temp_dir().join(format!("checkpoint_test_{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        let mut config = get_test_config();
+        config.target_path.path = folder.to_string_lossy().to_string();
+        let mut other = get_test_config();
+        other.business.study_id = 99;
+        let checkpoint = Checkpoint { scope: get_scope(&other), ..Default::default() };
+        let file_path = get_file_path(&config.target_path.path);
+        fs::write(&file_path, serde_json::to_string(&checkpoint).unwrap()).unwrap();
+
+        let refused = CheckpointStore::open(&config, true);
+        let fresh = CheckpointStore::open(&config, false);
+        fs::remove_dir_all(&folder).unwrap();
+
+        assert!(matches!(refused, Err(CustomError::CheckpointScopeMismatch)));
+        assert!(fresh.is_ok());
+    }
+}
@@ -5,4 +5,13 @@ use clap::Parser;
 pub struct CLi {
     // #[arg(short, long)]
     pub path: String,
+    // Skip tables finished by a previous run with the same config scope
+    #[arg(long)]
+    pub resume: bool,
+    // Print the select queries, estimates and target actions without copying anything
+    #[arg(long)]
+    pub plan: bool,
+    // Run COUNT(*) over every select of the plan, needs --plan
+    #[arg(long, requires = "plan")]
+    pub plan_counts: bool,
 }
@@ -1,15 +1,21 @@
-use serde_derive::Deserialize;
+use serde_derive::{ Deserialize, Serialize };
 
+use std::collections::HashMap;
 use std::fs;
-use toml;
 
+use crate::custom_error::{ CustomError, CustomResult };
 use crate::logger::LogLevel;
 
-#[derive(Debug, Deserialize, Clone)]
+// Redshift tables scoped by subject, every other Redshift table is copied whole
+const REDSHIFT_SUBJECT_TABLES: [&str; 2] = ["audit", "records_trail"];
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct TablesConfig {
     pub batch_tables: Vec<String>,
+    #[allow(dead_code)]
     pub partitioned_tables: Vec<String>,
     pub double_partitioned_tables: Vec<String>,
+    #[allow(dead_code)]
     pub triple_partitioned_tables: Vec<String>,
     pub redshift_tables: Vec<String>,
 }
@@ -23,7 +29,7 @@ pub struct DbConfig {
     pub database: String,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct BatchConfig {
     pub study_id: i64,
     pub area_id: u8,
@@ -31,6 +37,74 @@ pub struct BatchConfig {
     pub subject_id: Option<i64>,
     pub job_id: Option<i64>,
     pub limit: Option<i64>,
+    // Subjects of the limited source roots as SQL literals, picked at startup so the
+    // Redshift tables keep the same subjects
+    #[serde(skip_deserializing)]
+    pub subjects: Option<Vec<String>>,
+}
+
+impl BatchConfig {
+    // Restricts `column` to the subjects kept by the limit, None when nothing is limited
+    pub fn get_limit_condition(&self, column: &str) -> Option<String> {
+        self.subjects.as_ref().map(|subjects| get_in_condition(column, subjects))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SampleRoot {
+    // Distinct subject_id values of the subjects table
+    #[default]
+    Subjects,
+    Issues,
+    BatchRuns,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct SampleConfig {
+    // Keeps only the rows tied to `size` random roots instead of the whole scope
+    pub enabled: bool,
+    pub root: SampleRoot,
+    pub size: u64,
+    // The same seed picks the same roots from the same source data
+    pub seed: u64,
+    // Splits the size across the values of this root table column by their share of the roots
+    pub stratify_by: Option<String>,
+    // Double partitioned table the subjects are picked from
+    pub subjects_table: String,
+    // Picked from the source at startup as SQL literals, so source and target see the same roots
+    #[serde(skip_deserializing)]
+    pub roots: Option<Vec<String>>,
+}
+
+impl Default for SampleConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            root: SampleRoot::Subjects,
+            size: 100,
+            seed: 0,
+            stratify_by: None,
+            subjects_table: "cb_study_data".to_string(),
+            roots: None,
+        }
+    }
+}
+
+impl SampleConfig {
+    // Restricts `column` to the picked roots, None when `root` isn't what is sampled
+    pub fn get_condition(&self, root: SampleRoot, column: &str) -> Option<String> {
+        let roots = self.roots.as_ref().filter(|_| self.root == root)?;
+
+        Some(get_in_condition(column, roots))
+    }
+}
+
+// An empty key list matches no rows
+fn get_in_condition(column: &str, keys: &[String]) -> String {
+    let keys = if keys.is_empty() { "NULL".to_string() } else { keys.join(", ") };
+
+    format!("{} IN ({})", column, keys)
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -38,9 +112,28 @@ pub struct DbTechnology {
     pub category: String,
 }
 
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExistingFolder {
+    // Files of an earlier run in the same folder are replaced
+    #[default]
+    Overwrite,
+    // A non-empty output folder fails the run, unless it is resumed
+    Refuse,
+    // Every run writes to its own run_<timestamp> subfolder
+    Timestamped,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct TargetPath {
     pub path: String,
+    #[serde(default = "default_write_files")]
+    pub write_files: bool,
+    #[serde(default)]
+    pub existing_folder: ExistingFolder,
+}
+
+fn default_write_files() -> bool {
+    true
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -48,6 +141,306 @@ pub struct LogsConfig {
     pub log_level: LogLevel,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictMode {
+    // Plain INSERT, the first duplicate key aborts the load
+    #[default]
+    Fail,
+    // MySQL only: INSERT IGNORE, Redshift has nothing to skip duplicates with
+    Ignore,
+    // MySQL: ON DUPLICATE KEY UPDATE, Redshift: DELETE matching keys before INSERT
+    Upsert,
+    // MySQL: REPLACE, Redshift: DELETE matching keys before INSERT
+    Replace,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnscopedPurge {
+    // A batch table without the cb_ prefix has no scope to purge by and fails the run
+    #[default]
+    Fail,
+    // The table isn't purged, its rows are loaded on top of the target rows
+    Skip,
+    // Every row of the target table is deleted
+    DeleteAll,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct InsertConfig {
+    pub conflict_mode: ConflictMode,
+    // Per-table overrides, keyed by table name or double partitioned table prefix
+    pub tables: HashMap<String, ConflictMode>,
+    // Delete the rows matching the extraction scope from the target before inserting
+    pub purge_before_load: bool,
+    // What the purge does with batch tables it has no scope for
+    pub unscoped_purge: UnscopedPurge,
+}
+
+impl InsertConfig {
+    pub fn get_conflict_mode(&self, table: &str) -> ConflictMode {
+        match self.tables.get(table) {
+            Some(mode) => *mode,
+            None => self.conflict_mode,
+        }
+    }
+
+    pub fn get_partitioned_conflict_mode(&self, table: &str, table_prefix: &str) -> ConflictMode {
+        match self.tables.get(table).or(self.tables.get(table_prefix)) {
+            Some(mode) => *mode,
+            None => self.conflict_mode,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LoadMode {
+    // Every table is committed as soon as it is loaded
+    #[default]
+    Autocommit,
+    // The whole load is one transaction, rolled back on the first failure
+    Transaction,
+    // One transaction with a savepoint per table, so each table is loaded all-or-nothing
+    Savepoint,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LoadMethod {
+    // Runs the generated INSERT statements
+    #[default]
+    Insert,
+    // Writes per-table TSV files and bulk loads them with LOAD DATA LOCAL INFILE
+    LoadData,
+    // Binds the extracted rows into prepared multi-row INSERTs, no SQL text involved
+    Direct,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct LoadConfig {
+    pub mode: LoadMode,
+    pub method: LoadMethod,
+    // Rows per INSERT for batched and prepared INSERTs
+    pub batch_size: usize,
+}
+
+impl Default for LoadConfig {
+    fn default() -> Self {
+        Self {
+            mode: LoadMode::default(),
+            method: LoadMethod::default(),
+            batch_size: 1000,
+        }
+    }
+}
+
+impl LoadConfig {
+    // Whether extracted rows have to be kept next to the generated SQL
+    pub fn keeps_table_data(&self) -> bool {
+        self.method != LoadMethod::Insert
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct RetryConfig {
+    // Total attempts including the first one, 1 disables retries
+    pub max_attempts: u32,
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_delay_ms: 500,
+            max_delay_ms: 10000,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimeoutPolicy {
+    // A timed out extract query fails the run
+    #[default]
+    Stop,
+    // The table is left out of the output and the run carries on
+    Skip,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    // Limit for every extract query, unset means no limit
+    pub statement_timeout_ms: Option<u64>,
+    // Per-table overrides, keyed by table name or double partitioned table prefix
+    pub tables: HashMap<String, u64>,
+    pub on_timeout: TimeoutPolicy,
+}
+
+impl TimeoutConfig {
+    pub fn get_statement_timeout(&self, table: &str) -> Option<u64> {
+        self.tables.get(table).copied().or(self.statement_timeout_ms)
+    }
+
+    pub fn get_partitioned_statement_timeout(
+        &self,
+        table: &str,
+        table_prefix: &str
+    ) -> Option<u64> {
+        self.tables
+            .get(table)
+            .or(self.tables.get(table_prefix))
+            .copied()
+            .or(self.statement_timeout_ms)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PoolConfig {
+    pub min_connections: usize,
+    pub max_connections: usize,
+    pub connect_timeout_ms: u64,
+    // Unset read and write timeouts let long extracts run without a limit
+    pub read_timeout_ms: Option<u64>,
+    pub write_timeout_ms: Option<u64>,
+    // Pings pooled connections before handing them out
+    pub check_health: bool,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            min_connections: 1,
+            max_connections: 10,
+            connect_timeout_ms: 10000,
+            read_timeout_ms: None,
+            write_timeout_ms: None,
+            check_health: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ExtractConfig {
+    // Tables extracted at the same time, each worker uses its own pooled connection
+    pub workers: usize,
+    // Values that can't be decoded are written as NULL with a warning instead of failing
+    pub lenient_decoding: bool,
+}
+
+impl Default for ExtractConfig {
+    fn default() -> Self {
+        Self { workers: 1, lenient_decoding: false }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ErrorsConfig {
+    // Skip tables that fail to extract or load and report them at the end of the run
+    pub continue_on_error: bool,
+}
+
+// A reference the schema doesn't declare, tables can be double partitioned prefixes
+#[derive(Debug, Deserialize, Clone)]
+pub struct ReferenceRule {
+    pub table: String,
+    pub column: String,
+    pub parent_table: String,
+    pub parent_column: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ReferencesConfig {
+    // Checks every foreign key value of the extracted rows before the load
+    pub check: bool,
+    // Orphan rows fail the run instead of only being reported
+    pub fail_on_orphans: bool,
+    pub rules: Vec<ReferenceRule>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaPolicy {
+    Ignore,
+    // Listed in the run summary, the load goes on
+    Warn,
+    // Blocks the load before anything is written
+    Fail,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SchemaConfig {
+    // Compares the columns of every MySQL table on source and target before the extract
+    pub check: bool,
+    // Source columns or tables the target doesn't have
+    pub missing_columns: SchemaPolicy,
+    // Target columns the source doesn't have
+    pub extra_columns: SchemaPolicy,
+    // Target types that can't hold every source value
+    pub type_narrowing: SchemaPolicy,
+    pub nullability: SchemaPolicy,
+    pub defaults: SchemaPolicy,
+}
+
+impl Default for SchemaConfig {
+    fn default() -> Self {
+        Self {
+            check: false,
+            missing_columns: SchemaPolicy::Fail,
+            extra_columns: SchemaPolicy::Warn,
+            type_narrowing: SchemaPolicy::Fail,
+            nullability: SchemaPolicy::Warn,
+            defaults: SchemaPolicy::Ignore,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct MetricsConfig {
+    // Prometheus textfile collector file, written when the run ends
+    pub textfile: Option<String>,
+    // Address the /metrics endpoint listens on while the run is in progress
+    pub listen: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ReportConfig {
+    // Writes run_report.json to the output folder
+    pub write_file: bool,
+    // Prints the JSON report to stdout, the run summary and logs go to stderr
+    pub print: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct DeltaConfig {
+    // Diffs the scoped source rows with the target by primary key and only writes the changes
+    pub enabled: bool,
+    // Target rows in scope that are gone from the source are deleted
+    pub delete_missing: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct VerifyConfig {
+    // Counts every loaded table in source and target after the load
+    pub row_counts: bool,
+    // Hashes the scoped rows of every loaded table in primary key order on source and target
+    pub checksums: bool,
+    // Lists the primary keys of differing rows, keeps one hash per row in memory
+    pub report_keys: bool,
+    // A count mismatch fails the run instead of only being reported
+    pub fail_on_mismatch: bool,
+}
+
 // Top level struct to hold the TOML data.
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
@@ -59,16 +452,95 @@ pub struct Config {
     pub technology: DbTechnology,
     pub business: BatchConfig,
     pub log: LogsConfig,
+    #[serde(default)]
+    pub insert: InsertConfig,
+    #[serde(default)]
+    pub load: LoadConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub pool: PoolConfig,
+    #[serde(default)]
+    pub extract: ExtractConfig,
+    #[serde(default)]
+    pub timeout: TimeoutConfig,
+    #[serde(default)]
+    pub errors: ErrorsConfig,
+    #[serde(default)]
+    pub verify: VerifyConfig,
+    #[serde(default)]
+    pub delta: DeltaConfig,
+    #[serde(default)]
+    pub report: ReportConfig,
+    #[serde(default)]
+    pub references: ReferencesConfig,
+    #[serde(default)]
+    pub schema: SchemaConfig,
+    #[serde(default)]
+    pub sample: SampleConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+}
+
+impl Config {
+    // Direct loads bind the rows themselves, so SQL text is only needed for the output files
+    pub fn renders_insert_sql(&self) -> bool {
+        self.target_path.write_files || self.load.method != LoadMethod::Direct
+    }
+
+    // Whether extracted rows are kept next to the generated SQL
+    pub fn keeps_table_data(&self) -> bool {
+        self.loads_table_data() || self.references.check
+    }
+
+    // Delta statements are always loaded as SQL text
+    pub fn loads_table_data(&self) -> bool {
+        !self.delta.enabled && self.load.keeps_table_data()
+    }
+
+    // A limited or sampled extract only sees part of the scope, the target rows outside
+    // of it aren't missing from the source
+    pub fn deletes_missing_rows(&self) -> bool {
+        self.delta.delete_missing && self.business.limit.is_none() && !self.sample.enabled
+    }
+
+    // Settings that parse but can't run, caught before anything connects
+    pub fn validate(&self) -> CustomResult<()> {
+        for table in &self.tables.redshift_tables {
+            let mode = self.insert.get_conflict_mode(table);
+            if mode == ConflictMode::Ignore {
+                return Err(CustomError::UnsupportedConflictMode { table: table.clone(), mode });
+            }
+        }
+
+        // The limit keeps the children of the limited roots, a table with no link to them
+        // would come out whole or cut on its own
+        if self.business.limit.is_some() && !self.sample.enabled {
+            let unlinked = self.tables.batch_tables
+                .iter()
+                .find(|table| !table.starts_with("cb_"))
+                .or_else(|| {
+                    self.tables.redshift_tables
+                        .iter()
+                        .find(|table| !REDSHIFT_SUBJECT_TABLES.contains(&table.as_str()))
+                });
+            if let Some(table) = unlinked {
+                return Err(CustomError::UnlinkedLimitedTable { table: table.clone() });
+            }
+        }
+
+        Ok(())
+    }
 }
 
 pub fn read_config(path: &str) -> Config {
-    println!("Reading config file: {}", path);
+    eprintln!("Reading config file: {}", path);
     let content_result = fs::read_to_string(path);
 
     let contents = match content_result {
         Ok(contents) => contents,
         Err(error) => {
-            println!("Error reading file: {}", error);
+            eprintln!("Error reading file: {}", error);
             std::process::exit(1);
         }
     };
@@ -77,12 +549,116 @@ pub fn read_config(path: &str) -> Config {
     let data: Config = match data_result {
         Ok(data) => data,
         Err(error) => {
-            println!("Error parsing file: {}", error);
+            eprintln!("Error parsing file: {}", error);
             std::process::exit(1);
         }
     };
-    println!("Read config file: {}", path);
-    println!("{:#?}", data);
+    if let Err(error) = data.validate() {
+        eprintln!("Invalid config: {}", error);
+        std::process::exit(1);
+    }
+    eprintln!("Read config file: {}", path);
 
     data
 }
+
+// Smallest config that parses, tests set the sections they exercise
+#[cfg(test)]
+pub fn get_test_config() -> Config {
+    let contents =
+        r#"
+        [source]
+        username = "user"
+        password = "pass"
+        host = "localhost"
+        port = "3306"
+        database = "source"
+
+        [redshift_db]
+        username = "user"
+        password = "pass"
+        host = "localhost"
+        port = "5439"
+        database = "redshift"
+
+        [target_path]
+        path = "/tmp/batch_data_copy"
+
+        [tables]
+        batch_tables = []
+        partitioned_tables = []
+        double_partitioned_tables = []
+        triple_partitioned_tables = []
+        redshift_tables = []
+
+        [technology]
+        category = "mysql"
+
+        [log]
+        log_level = "Warn"
+
+        [business]
+        study_id = 1
+        area_id = 2
+        lifecycle_id = 3
+        "#;
+
+    toml::from_str(contents).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::custom_error::CustomError;
+
+    use super::get_test_config;
+
+    #[test]
+    fn limit_rejects_batch_tables_without_a_link_to_the_roots() {
+        let mut config = get_test_config();
+        config.business.limit = Some(10);
+        config.tables.batch_tables = vec!["cb_batch_runs".to_string(), "settings".to_string()];
+
+        assert!(
+            matches!(
+                config.validate(),
+                Err(CustomError::UnlinkedLimitedTable { table }) if table == "settings"
+            )
+        );
+    }
+
+    #[test]
+    fn limit_rejects_redshift_tables_not_scoped_by_subject() {
+        let mut config = get_test_config();
+        config.business.limit = Some(10);
+        config.tables.redshift_tables = vec!["audit".to_string(), "lookups".to_string()];
+
+        assert!(
+            matches!(
+                config.validate(),
+                Err(CustomError::UnlinkedLimitedTable { table }) if table == "lookups"
+            )
+        );
+    }
+
+    #[test]
+    fn unlinked_tables_are_fine_without_a_limit_or_with_sampling() {
+        let mut config = get_test_config();
+        config.tables.batch_tables = vec!["settings".to_string()];
+        config.tables.redshift_tables = vec!["lookups".to_string()];
+        assert!(config.validate().is_ok());
+
+        config.business.limit = Some(10);
+        config.sample.enabled = true;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn linked_tables_take_the_limit() {
+        let mut config = get_test_config();
+        config.business.limit = Some(10);
+        config.tables.batch_tables = vec!["cb_batch_runs".to_string()];
+        config.tables.redshift_tables = vec!["audit".to_string(), "records_trail".to_string()];
+
+        assert!(config.validate().is_ok());
+    }
+}
@@ -1,26 +1,437 @@
+use std::{ error::Error, fmt, io };
+
+use crate::config::ConflictMode;
+use crate::retry::{ is_transient_mysql_error, is_transient_sqlx_error };
+use crate::timeout::{ is_timeout_mysql_error, is_timeout_sqlx_error };
+
 pub type CustomResult<T> = core::result::Result<T, CustomError>;
+pub type ErrorSource = Box<dyn Error + Send + Sync>;
+
+// Longest statement or cause excerpt kept in an error
+const SQL_EXCERPT_LENGTH: usize = 300;
+
+// Where a failing statement ran, filled in by the callers that know it
+#[derive(Debug, Default)]
+pub struct QueryContext {
+    pub table: Option<String>,
+    // Excerpt of the statement with its literals redacted
+    pub sql: Option<String>,
+    pub source: Option<ErrorSource>,
+}
 
 #[derive(Debug)]
 pub enum CustomError {
-    DbQueryExecution(String),
-    DbTableStructure,
-    DbConnection,
+    DbQueryExecution(QueryContext),
+    // Lock waits, deadlocks, dropped connections and the like, worth another attempt
+    DbTransient(QueryContext),
+    // The statement ran past its timeout and was cancelled by the server
+    DbQueryTimeout(QueryContext),
+    DbTableStructure {
+        table: String,
+        source: ErrorSource,
+    },
+    DbConnection {
+        database: String,
+        source: ErrorSource,
+    },
+    // A value that can't be converted, row is its 1-based position in the result set
+    DbValueDecoding {
+        table: String,
+        column: String,
+        row: usize,
+        sql_type: String,
+        source: ErrorSource,
+    },
     DbTechnology,
-    FileCreationError,
-    FileDataInsertionError,
-    FolderCreationError,
+    FileCreationError {
+        path: String,
+        source: io::Error,
+    },
+    FileDataInsertionError {
+        path: String,
+        source: ErrorSource,
+    },
+    FolderCreationError {
+        path: String,
+        source: io::Error,
+    },
+    OutputFolderExists {
+        path: String,
+    },
     NotImplemented,
+    CheckpointRead {
+        path: String,
+        source: ErrorSource,
+    },
+    CheckpointScopeMismatch,
+    // Loaded table whose source and target row counts differ from the extracted rows
+    RowCountMismatch {
+        table: String,
+        extracted: usize,
+        source_rows: u64,
+        target_rows: u64,
+    },
+    ChecksumMismatch {
+        table: String,
+    },
+    DeltaWithoutTarget,
+    // Foreign key values with no parent row in the extract or the target
+    OrphanRows {
+        table: String,
+        column: String,
+        parent_table: String,
+        orphans: usize,
+    },
+    MissingPrimaryKey {
+        table: String,
+    },
+    // LOAD DATA LOCAL turns duplicate keys into warnings and skips the rows
+    LoadDataSkippedRows {
+        table: String,
+        skipped: usize,
+    },
+    // Purge of a table with no extraction scope, which would empty the whole target table
+    UnscopedPurge {
+        table: String,
+    },
+    // The target database has no statement for this conflict mode
+    UnsupportedConflictMode {
+        table: String,
+        mode: ConflictMode,
+    },
+    // Table the limit can't narrow down to the children of the limited roots
+    UnlinkedLimitedTable {
+        table: String,
+    },
+    // Source and target columns differ in a way the schema policy blocks
+    SchemaMismatch {
+        table: String,
+        differences: usize,
+    },
+    // SIGINT or SIGTERM stopped the run
+    Cancelled,
+    // Tables that failed while the rest of the load went through
+    TablesFailed(Vec<CustomError>),
+}
+
+impl CustomError {
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::DbTransient(_))
+    }
+
+    pub fn with_table(mut self, table: &str) -> Self {
+        if let Some(context) = self.get_query_context() {
+            context.table = Some(table.to_string());
+        }
+        self
+    }
+
+    pub fn with_sql(mut self, sql: &str) -> Self {
+        if let Some(context) = self.get_query_context() {
+            context.sql = Some(redact_sql(sql));
+        }
+        self
+    }
+
+    // Multi-line description with the failing statement and every underlying cause
+    pub fn report(&self) -> String {
+        let mut report = self.to_string();
+        match self {
+            Self::DbQueryExecution(context) |
+            Self::DbTransient(context) |
+            Self::DbQueryTimeout(context) => {
+                if let Some(sql) = &context.sql {
+                    report.push_str(format!("\n  statement: {}", sql).as_str());
+                }
+            }
+            Self::TablesFailed(errors) => {
+                for err in errors {
+                    let table_report = err.report().replace('\n', "\n    ");
+                    report.push_str(format!("\n  - {}", table_report).as_str());
+                }
+            }
+            _ => {}
+        }
+
+        // Driver messages quote the offending values, duplicate keys and bad values included
+        let mut source = self.source();
+        while let Some(err) = source {
+            let message = redact_message(&err.to_string());
+            report.push_str(format!("\n  caused by: {}", message).as_str());
+            source = err.source();
+        }
+
+        report
+    }
+
+    fn get_query_context(&mut self) -> Option<&mut QueryContext> {
+        match self {
+            Self::DbQueryExecution(context) |
+            Self::DbTransient(context) |
+            Self::DbQueryTimeout(context) => Some(context),
+            _ => None,
+        }
+    }
 }
 
 impl From<sqlx::error::Error> for CustomError {
     fn from(e: sqlx::error::Error) -> Self {
-        Self::DbQueryExecution(e.to_string())
+        let is_timeout = is_timeout_sqlx_error(&e);
+        let is_transient = is_transient_sqlx_error(&e);
+        let context = QueryContext { source: Some(Box::new(e)), ..Default::default() };
+
+        if is_timeout {
+            Self::DbQueryTimeout(context)
+        } else if is_transient {
+            Self::DbTransient(context)
+        } else {
+            Self::DbQueryExecution(context)
+        }
+    }
+}
+
+impl From<mysql::Error> for CustomError {
+    fn from(e: mysql::Error) -> Self {
+        let is_timeout = is_timeout_mysql_error(&e);
+        let is_transient = is_transient_mysql_error(&e);
+        let context = QueryContext { source: Some(Box::new(e)), ..Default::default() };
+
+        if is_timeout {
+            Self::DbQueryTimeout(context)
+        } else if is_transient {
+            Self::DbTransient(context)
+        } else {
+            Self::DbQueryExecution(context)
+        }
+    }
+}
+
+impl Error for CustomError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::DbQueryExecution(context) |
+            Self::DbTransient(context) |
+            Self::DbQueryTimeout(context) =>
+                context.source.as_ref().map(|source| source.as_ref() as &(dyn Error + 'static)),
+            Self::DbTableStructure { source, .. } |
+            Self::DbConnection { source, .. } |
+            Self::DbValueDecoding { source, .. } |
+            Self::FileDataInsertionError { source, .. } |
+            Self::CheckpointRead { source, .. } => Some(source.as_ref()),
+            Self::FileCreationError { source, .. } | Self::FolderCreationError { source, .. } =>
+                Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CustomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::DbQueryExecution(context) =>
+                write!(f, "Query failed{}", get_table_suffix(context)),
+            Self::DbTransient(context) =>
+                write!(f, "Transient database error{}", get_table_suffix(context)),
+            Self::DbQueryTimeout(context) =>
+                write!(f, "Query timed out{}", get_table_suffix(context)),
+            Self::DbTableStructure { table, .. } =>
+                write!(f, "Can't read the structure of table {}", table),
+            Self::DbConnection { database, .. } =>
+                write!(f, "Can't connect to database {}", database),
+            Self::DbValueDecoding { table, column, row, sql_type, .. } =>
+                write!(
+                    f,
+                    "Can't decode column {} ({}) of row {} in table {}",
+                    column,
+                    sql_type,
+                    row,
+                    table
+                ),
+            Self::DbTechnology => write!(f, "Unsupported target technology"),
+            Self::FileCreationError { path, .. } => write!(f, "Can't create file {}", path),
+            Self::FileDataInsertionError { path, .. } => write!(f, "Can't write file {}", path),
+            Self::FolderCreationError { path, .. } => write!(f, "Can't create folder {}", path),
+            Self::OutputFolderExists { path } =>
+                write!(f, "Output folder {} already exists and is not empty", path),
+            Self::NotImplemented => write!(f, "Not implemented"),
+            Self::CheckpointRead { path, .. } => write!(f, "Can't read checkpoint {}", path),
+            Self::CheckpointScopeMismatch =>
+                write!(f, "Checkpoint was written for a different config scope"),
+            Self::RowCountMismatch { table, extracted, source_rows, target_rows } =>
+                write!(
+                    f,
+                    "Row counts of table {} don't match: {} extracted, {} in source, {} in target",
+                    table,
+                    extracted,
+                    source_rows,
+                    target_rows
+                ),
+            Self::ChecksumMismatch { table } =>
+                write!(f, "Checksums of table {} don't match between source and target", table),
+            Self::OrphanRows { table, column, parent_table, orphans } =>
+                write!(
+                    f,
+                    "{} values of {}.{} have no parent row in {}",
+                    orphans,
+                    table,
+                    column,
+                    parent_table
+                ),
+            Self::DeltaWithoutTarget => write!(f, "Delta sync needs a target DB to compare with"),
+            Self::SchemaMismatch { table, differences } =>
+                write!(
+                    f,
+                    "Table {} has {} blocking schema differences with the target",
+                    table,
+                    differences
+                ),
+            Self::MissingPrimaryKey { table } =>
+                write!(f, "Table {} has no primary key to match rows by", table),
+            Self::LoadDataSkippedRows { table, skipped } =>
+                write!(f, "LOAD DATA skipped {} duplicate rows of table {}", skipped, table),
+            Self::UnscopedPurge { table } =>
+                write!(f, "Table {} has no scope to purge by, see insert.unscoped_purge", table),
+            Self::UnsupportedConflictMode { table, mode } =>
+                write!(f, "Conflict mode {:?} isn't supported for table {}", mode, table),
+            Self::UnlinkedLimitedTable { table } =>
+                write!(f, "Table {} has no link to the limited roots, drop it or the limit", table),
+            Self::Cancelled => write!(f, "Run cancelled by signal"),
+            Self::TablesFailed(errors) => write!(f, "{} tables failed", errors.len()),
+        }
     }
 }
 
-impl std::error::Error for CustomError {}
-impl core::fmt::Display for CustomError {
-    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::result::Result<(), core::fmt::Error> {
-        write!(f, "{self:?}")
+fn get_table_suffix(context: &QueryContext) -> String {
+    match &context.table {
+        Some(table) => format!(" on table {}", table),
+        None => String::new(),
+    }
+}
+
+// Replaces quoted literals, which hold the copied data, and cuts the statement short
+pub fn redact_sql(sql: &str) -> String {
+    redact_literals(sql, true)
+}
+
+// Same for error messages, where an apostrophe inside a word, as in "doesn't", isn't a quote
+fn redact_message(message: &str) -> String {
+    redact_literals(message, false)
+}
+
+fn redact_literals(text: &str, quotes_in_words: bool) -> String {
+    let mut result = String::new();
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    let mut previous: Option<char> = None;
+
+    for character in text.chars() {
+        if result.len() >= SQL_EXCERPT_LENGTH {
+            result.push_str("...");
+            break;
+        }
+
+        let in_word = previous.is_some_and(char::is_alphanumeric);
+        match quote {
+            Some(_) if escaped => {
+                escaped = false;
+            }
+            Some(_) if character == '\\' => {
+                escaped = true;
+            }
+            Some(opening) if character == opening => {
+                result.push(character);
+                quote = None;
+            }
+            Some(_) => {}
+            None if character == '\'' && in_word && !quotes_in_words => result.push(character),
+            None if character == '\'' || character == '"' => {
+                result.push_str(format!("{}***", character).as_str());
+                quote = Some(character);
+            }
+            None if character.is_whitespace() => {
+                if !result.ends_with(' ') {
+                    result.push(' ');
+                }
+            }
+            None => result.push(character),
+        }
+        previous = Some(character);
+    }
+
+    result.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ redact_message, redact_sql, CustomError, SQL_EXCERPT_LENGTH };
+
+    #[test]
+    fn quoted_literals_are_replaced() {
+        assert_eq!(
+            redact_sql("INSERT INTO users VALUES (1, 'secret', \"other\")"),
+            "INSERT INTO users VALUES (1, '***', \"***\")"
+        );
+    }
+
+    #[test]
+    fn escaped_quotes_stay_inside_the_literal() {
+        assert_eq!(
+            redact_sql("SELECT * FROM t WHERE a = 'it\\'s' AND b = 2"),
+            "SELECT * FROM t WHERE a = '***' AND b = 2"
+        );
+    }
+
+    #[test]
+    fn whitespace_is_collapsed() {
+        assert_eq!(redact_sql("\n  SELECT *\n\tFROM   t\n"), "SELECT * FROM t");
+    }
+
+    #[test]
+    fn long_statements_are_cut() {
+        let sql = format!("SELECT {} FROM t", "a, ".repeat(200));
+        let result = redact_sql(&sql);
+
+        assert!(result.ends_with("..."));
+        assert_eq!(result.len(), SQL_EXCERPT_LENGTH + 3);
+    }
+
+    #[test]
+    fn message_values_are_replaced_and_words_kept() {
+        assert_eq!(
+            redact_message("ERROR 1062 (23000): Duplicate entry '42-jane' for key 'PRIMARY'"),
+            "ERROR 1062 (23000): Duplicate entry '***' for key '***'"
+        );
+        assert_eq!(
+            redact_message("Table 'db.t' doesn't exist"),
+            "Table '***' doesn't exist"
+        );
+    }
+
+    #[test]
+    fn report_redacts_the_causes() {
+        let err = CustomError::DbValueDecoding {
+            table: "users".to_string(),
+            column: "email".to_string(),
+            row: 3,
+            sql_type: "int".to_string(),
+            source: "Couldn't convert Bytes(\"jane@example.com\") to int".into(),
+        };
+
+        assert_eq!(
+            err.report(),
+            "Can't decode column email (int) of row 3 in table users\n  \
+             caused by: Couldn't convert Bytes(\"***\") to int"
+        );
+    }
+
+    #[test]
+    fn long_causes_are_cut() {
+        let err = CustomError::DbTableStructure {
+            table: "t".to_string(),
+            source: "x".repeat(1000).into(),
+        };
+
+        assert!(err.report().ends_with("..."));
+        assert!(err.report().len() < 400);
     }
 }
@@ -0,0 +1,109 @@
+use std::{ fs::{ self, File }, io::{ self, BufWriter, Write }, path::Path };
+
+use sqlx::types::chrono::Local;
+
+use crate::{
+    config::{ ExistingFolder, TargetPath },
+    custom_error::{ CustomError, CustomResult },
+};
+
+const RUN_FOLDER_PREFIX: &str = "run_";
+
+// Writes to a temp file next to the target, syncs it and renames it over the target,
+// so readers only ever see the previous file or the complete new one
+pub fn write_atomically<F>(file_path: &str, write: F) -> CustomResult<()>
+    where F: FnOnce(&mut BufWriter<File>) -> io::Result<()>
+{
+    let temp_path = get_temp_path(file_path);
+    let file = File::create(&temp_path).map_err(|source| CustomError::FileCreationError {
+        path: temp_path.clone(),
+        source,
+    })?;
+
+    let mut writer = BufWriter::new(file);
+    let result = write(&mut writer)
+        .and_then(|_| writer.flush())
+        .and_then(|_| writer.get_ref().sync_all())
+        .and_then(|_| fs::rename(&temp_path, file_path));
+
+    if let Err(source) = result {
+        let _ = fs::remove_file(&temp_path);
+        return Err(CustomError::FileDataInsertionError {
+            path: file_path.to_string(),
+            source: Box::new(source),
+        });
+    }
+
+    sync_parent_folder(file_path);
+    Ok(())
+}
+
+pub fn write_bytes_atomically(file_path: &str, data: &[u8]) -> CustomResult<()> {
+    write_atomically(file_path, |writer| writer.write_all(data))
+}
+
+// Picks the folder this run writes to according to the existing folder policy
+pub fn resolve_output_folder(target_path: &TargetPath, resume: bool) -> CustomResult<String> {
+    let logger = crate::logger::Logger::new();
+    let path = target_path.path.clone();
+
+    match target_path.existing_folder {
+        ExistingFolder::Overwrite => Ok(path),
+        // A resumed run picks up the folder the interrupted run left behind
+        ExistingFolder::Refuse if resume => Ok(path),
+        ExistingFolder::Refuse if is_non_empty_folder(&path) => {
+            Err(CustomError::OutputFolderExists { path })
+        }
+        ExistingFolder::Refuse => Ok(path),
+        ExistingFolder::Timestamped => {
+            if resume {
+                if let Some(latest) = get_latest_run_folder(&path) {
+                    logger.warn(format!("Resuming in output folder {}", latest).as_str());
+                    return Ok(latest);
+                }
+            }
+
+            let timestamp = Local::now().format("%Y%m%d_%H%M%S");
+            Ok(format!("{}/{}{}", path, RUN_FOLDER_PREFIX, timestamp))
+        }
+    }
+}
+
+fn get_temp_path(file_path: &str) -> String {
+    let path = Path::new(file_path);
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let temp_name = format!(".{}.{}.tmp", file_name, std::process::id());
+
+    path.with_file_name(temp_name).to_string_lossy().to_string()
+}
+
+// Makes the rename itself durable, not supported on every platform so failures are ignored
+fn sync_parent_folder(file_path: &str) {
+    if let Some(parent) = Path::new(file_path).parent() {
+        if let Ok(folder) = File::open(parent) {
+            let _ = folder.sync_all();
+        }
+    }
+}
+
+fn is_non_empty_folder(path: &str) -> bool {
+    match fs::read_dir(path) {
+        Ok(mut entries) => entries.next().is_some(),
+        Err(_) => false,
+    }
+}
+
+// Timestamped names sort chronologically, so the latest run is the greatest name
+fn get_latest_run_folder(path: &str) -> Option<String> {
+    fs::read_dir(path)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| name.starts_with(RUN_FOLDER_PREFIX))
+        .max()
+        .map(|name| format!("{}/{}", path, name))
+}
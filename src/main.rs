@@ -1,4 +1,7 @@
+use std::{ process::ExitCode, thread, time::Instant };
+
 use clap::Parser;
+use tokio::runtime::Handle;
 mod config;
 mod cli;
 mod logger;
@@ -8,37 +11,245 @@ mod custom_error;
 mod mysql;
 use mysql::insert_query_generator::InsertQueryGenerator as MySqlInsertQueryGenerator;
 use mysql::data_saver::DataSaver as MySqlDataSaver;
+use mysql::db::ConnectionManager as MySqlConnectionManager;
+use mysql::verifier::LoadVerifier as MySqlLoadVerifier;
+use mysql::planner::Planner as MySqlPlanner;
+use mysql::reference_checker::ReferenceChecker as MySqlReferenceChecker;
+use mysql::schema_checker::SchemaChecker as MySqlSchemaChecker;
+use mysql::sampler::Sampler as MySqlSampler;
 
 mod redshift;
 use redshift::insert_query_generator::InsertQueryGenerator as RedshiftInsertQueryGenerator;
-use mysql::data_saver::DataSaver as RedshiftDataSaver;
+use redshift::data_saver::DataSaver as RedshiftDataSaver;
+use redshift::planner::Planner as RedshiftPlanner;
 use crate::{
+    checkpoint::CheckpointStore,
+    config::Config,
     custom_error::CustomError,
+    summary::RunSummary,
     traits::{ TechnologyInsertGeneratorTrait, DataSaverTrait },
 };
 mod traits;
+mod checkpoint;
+mod retry;
+mod timeout;
+mod summary;
+mod cancellation;
+mod workers;
+mod files;
+mod plan;
+mod metrics;
+mod report;
 
 #[tokio::main]
-async fn main() -> CustomResult<()> {
+async fn main() -> ExitCode {
     let cli_args = CLi::parse();
-    let config = config::read_config(&cli_args.path);
+    let mut config = config::read_config(&cli_args.path);
 
     logger::Logger::init(config.log.log_level);
+    cancellation::install_signal_handlers();
+    let started = Instant::now();
+    if let Some(address) = &config.metrics.listen {
+        // Monitoring is optional, the run goes on without the endpoint
+        if let Err(err) = metrics::serve(address) {
+            let logger = logger::Logger::new();
+            logger.error(format!("Can't serve metrics on {}: {}", address, err).as_str());
+        }
+    }
+
+    let summary = RunSummary::new();
+    // One pool per MySQL database for the whole run, sampling included
+    let connections = MySqlConnectionManager::new(&config);
+    let result = if cli_args.plan {
+        sample_roots(&mut config, &connections).and_then(|_| {
+            run_plan(&config, &cli_args, &connections)
+        })
+    } else {
+        files::resolve_output_folder(&config.target_path, cli_args.resume).and_then(|path| {
+            config.target_path.path = path;
+            let result = sample_roots(&mut config, &connections).and_then(|_| {
+                run(&config, &cli_args, &summary, &connections)
+            });
+            summary.print();
+            report::report(&config, &summary, &result);
+            result
+        })
+    };
+    // Plan runs and runs that fail before any table is copied report their outcome too
+    metrics::set_run_result(result.is_ok() && !summary.has_failures(), started.elapsed());
+    metrics::write_textfile(&config.metrics);
+
+    match result {
+        Ok(_) if summary.has_failures() => ExitCode::FAILURE,
+        Ok(_) => ExitCode::SUCCESS,
+        Err(CustomError::Cancelled) => {
+            eprintln!("Run cancelled, finished tables are in the checkpoint, rerun with --resume");
+            ExitCode::from(130)
+        }
+        Err(err) => {
+            eprintln!("Error: {}", err.report());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(
+    config: &Config,
+    cli_args: &CLi,
+    summary: &RunSummary,
+    connections: &MySqlConnectionManager
+) -> CustomResult<()> {
+    let checkpoint = CheckpointStore::open(config, cli_args.resume)?;
+
+    // Redshift and MySQL phases share nothing but the checkpoint and summary,
+    // so they run side by side
+    let (redshift_result, mysql_result) = tokio::task::block_in_place(|| {
+        thread::scope(|scope| {
+            let mysql_phase = scope.spawn(|| {
+                run_mysql_phase(config, &checkpoint, summary, connections)
+            });
+            let redshift_result = Handle::current().block_on(
+                run_redshift_phase(config, &checkpoint, summary)
+            );
+            let mysql_result = mysql_phase
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+
+            (redshift_result, mysql_result)
+        })
+    });
+
+    // Neither phase stops the other, so both can fail and both errors are kept
+    match (redshift_result, mysql_result) {
+        (Err(redshift_err), Err(mysql_err)) => Err(merge_errors(redshift_err, mysql_err)),
+        (Err(err), Ok(_)) | (Ok(_), Err(err)) => Err(err),
+        (Ok(_), Ok(_)) => Ok(()),
+    }
+}
+
+fn merge_errors(first: CustomError, second: CustomError) -> CustomError {
+    if matches!((&first, &second), (CustomError::Cancelled, CustomError::Cancelled)) {
+        return CustomError::Cancelled;
+    }
+
+    let mut errors: Vec<CustomError> = vec![];
+    for err in [first, second] {
+        match err {
+            CustomError::TablesFailed(table_errors) => errors.extend(table_errors),
+            err => errors.push(err),
+        }
+    }
+
+    CustomError::TablesFailed(errors)
+}
+
+// Roots are picked once up front, so every query on source and target sees the same sample,
+// and Redshift keeps the subjects of the limited source roots
+fn sample_roots(config: &mut Config, connections: &MySqlConnectionManager) -> CustomResult<()> {
+    if config.sample.enabled {
+        let roots = {
+            let sampler = MySqlSampler { config, connections };
+            sampler.sample()?
+        };
+        config.sample.roots = Some(roots);
+    } else if config.business.limit.is_some() && !config.tables.redshift_tables.is_empty() {
+        let subjects = {
+            let sampler = MySqlSampler { config, connections };
+            sampler.get_limited_subjects()?
+        };
+        config.business.subjects = Some(subjects);
+    }
+
+    Ok(())
+}
 
-    if config.tables.redshift_tables.len() > 0 {
-        let generator = RedshiftInsertQueryGenerator { config: &config };
-        let sql_statements = generator.generate().await?;
-        let saver = RedshiftDataSaver { config: &config };
-        saver.save(&sql_statements)?;
+// Dry run, nothing is fetched, written or loaded
+fn run_plan(
+    config: &Config,
+    cli_args: &CLi,
+    connections: &MySqlConnectionManager
+) -> CustomResult<()> {
+    if config.technology.category != "mysql" {
+        return Err(CustomError::DbTechnology);
     }
+    let mysql_planner = MySqlPlanner {
+        config,
+        connections,
+        count_rows: cli_args.plan_counts,
+    };
+    let mut plans = mysql_planner.plan()?;
+
+    let redshift_planner = RedshiftPlanner { config, count_rows: cli_args.plan_counts };
+    plans.extend(
+        tokio::task::block_in_place(|| Handle::current().block_on(redshift_planner.plan()))?
+    );
+
+    plan::print_plan(&plans);
+    Ok(())
+}
 
-    if config.technology.category == "mysql" {
-        let generator = MySqlInsertQueryGenerator { config: &config };
-        let sql_statements = generator.generate()?;
-        let saver = MySqlDataSaver { config: &config };
-        saver.save(&sql_statements)?;
+async fn run_redshift_phase(
+    config: &Config,
+    checkpoint: &CheckpointStore,
+    summary: &RunSummary
+) -> CustomResult<()> {
+    if config.tables.redshift_tables.is_empty() {
         return Ok(());
     }
 
-    Err(CustomError::DbTechnology)
+    let generator = RedshiftInsertQueryGenerator { config, checkpoint, summary };
+    let sql_statements = generator.generate().await?;
+    let saver = RedshiftDataSaver { config, summary };
+    saver.save(&sql_statements)
+}
+
+fn run_mysql_phase(
+    config: &Config,
+    checkpoint: &CheckpointStore,
+    summary: &RunSummary,
+    connections: &MySqlConnectionManager
+) -> CustomResult<()> {
+    if config.technology.category != "mysql" {
+        return Err(CustomError::DbTechnology);
+    }
+    if config.delta.enabled && config.target_db.is_none() {
+        return Err(CustomError::DeltaWithoutTarget);
+    }
+
+    // Runs before the extract so a blocked load doesn't leave half written output
+    if config.schema.check {
+        let checker = MySqlSchemaChecker { config, summary, connections };
+        checker.check()?;
+    }
+
+    let generator = MySqlInsertQueryGenerator {
+        config,
+        checkpoint,
+        summary,
+        connections,
+    };
+    let mut sql_statements = generator.generate()?;
+
+    if config.references.check {
+        let checker = MySqlReferenceChecker { config, summary, connections };
+        checker.check(&sql_statements)?;
+        if !config.loads_table_data() {
+            sql_statements.release_table_data();
+        }
+    }
+
+    let saver = MySqlDataSaver {
+        config,
+        checkpoint,
+        summary,
+        connections,
+    };
+    saver.save(&sql_statements)?;
+
+    if config.verify.row_counts || config.verify.checksums {
+        let verifier = MySqlLoadVerifier { config, summary, connections };
+        verifier.verify(&sql_statements)?;
+    }
+
+    Ok(())
 }
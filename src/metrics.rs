@@ -0,0 +1,352 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    io::{ BufRead, BufReader, Write },
+    net::{ TcpListener, TcpStream },
+    sync::Mutex,
+    thread,
+    time::{ Duration, SystemTime, UNIX_EPOCH },
+};
+
+use crate::{ config::MetricsConfig, files::write_bytes_atomically };
+
+const PREFIX: &str = "batch_data_copy";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// Upper bounds in seconds, from a quick lookup to a long bulk load
+const DURATION_BUCKETS: [f64; 11] = [
+    0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0,
+];
+// Upper bounds for the rows of one table, from a lookup table to the largest partitions
+const ROW_BUCKETS: [f64; 7] = [10.0, 100.0, 1e3, 1e4, 1e5, 1e6, 1e7];
+// Upper bounds for the bytes written for one table, 1 KB to 10 GB
+const BYTE_BUCKETS: [f64; 8] = [1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9, 1e10];
+
+struct Histogram {
+    bounds: &'static [f64],
+    // Observations per bucket, not cumulative
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self { bounds, buckets: vec![0; bounds.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(position) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.buckets[position] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+struct RunResult {
+    success: bool,
+    duration: Duration,
+    finished_at: SystemTime,
+}
+
+// Counters are keyed by (category, table), histograms by their one label
+struct Registry {
+    rows: BTreeMap<(String, String), u64>,
+    bytes: BTreeMap<(String, String), u64>,
+    table_rows: BTreeMap<String, Histogram>,
+    table_bytes: BTreeMap<String, Histogram>,
+    table_durations: BTreeMap<String, Histogram>,
+    query_durations: BTreeMap<String, Histogram>,
+    failures: BTreeMap<String, u64>,
+    retries: u64,
+    run: Option<RunResult>,
+}
+
+impl Registry {
+    const fn new() -> Self {
+        Self {
+            rows: BTreeMap::new(),
+            bytes: BTreeMap::new(),
+            table_rows: BTreeMap::new(),
+            table_bytes: BTreeMap::new(),
+            table_durations: BTreeMap::new(),
+            query_durations: BTreeMap::new(),
+            failures: BTreeMap::new(),
+            retries: 0,
+            run: None,
+        }
+    }
+}
+
+// Shared with the HTTP endpoint and the retry helpers, which run without the summary
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry::new());
+
+// Rows of one copied table, tables taken from the checkpoint aren't counted
+pub fn add_rows(category: &str, table: &str, rows: u64) {
+    let mut registry = REGISTRY.lock().unwrap();
+    *registry.rows.entry((category.to_string(), table.to_string())).or_default() += rows;
+    observe(&mut registry.table_rows, category, &ROW_BUCKETS, rows as f64);
+}
+
+pub fn add_bytes(category: &str, table: &str, bytes: u64) {
+    let mut registry = REGISTRY.lock().unwrap();
+    *registry.bytes.entry((category.to_string(), table.to_string())).or_default() += bytes;
+    observe(&mut registry.table_bytes, category, &BYTE_BUCKETS, bytes as f64);
+}
+
+// Time of a whole table, retries included, phase is extract or load
+pub fn observe_duration(phase: &str, elapsed: Duration) {
+    let mut registry = REGISTRY.lock().unwrap();
+    observe(&mut registry.table_durations, phase, &DURATION_BUCKETS, elapsed.as_secs_f64());
+}
+
+// Time of a single statement, kind is select or load
+pub fn observe_query(kind: &str, elapsed: Duration) {
+    let mut registry = REGISTRY.lock().unwrap();
+    observe(&mut registry.query_durations, kind, &DURATION_BUCKETS, elapsed.as_secs_f64());
+}
+
+fn observe(
+    histograms: &mut BTreeMap<String, Histogram>,
+    label: &str,
+    bounds: &'static [f64],
+    value: f64
+) {
+    histograms.entry(label.to_string()).or_insert_with(|| Histogram::new(bounds)).observe(value);
+}
+
+pub fn add_failure(category: &str) {
+    *REGISTRY.lock().unwrap().failures.entry(category.to_string()).or_default() += 1;
+}
+
+pub fn add_retry() {
+    REGISTRY.lock().unwrap().retries += 1;
+}
+
+pub fn set_run_result(success: bool, duration: Duration) {
+    REGISTRY.lock().unwrap().run = Some(RunResult {
+        success,
+        duration,
+        finished_at: SystemTime::now(),
+    });
+}
+
+// Serves the metrics on http://<address>/metrics until the process exits,
+// each request on its own thread so a slow client doesn't hold up the others
+pub fn serve(address: &str) -> std::io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            thread::spawn(move || {
+                if let Err(err) = respond(stream) {
+                    let logger = crate::logger::Logger::new();
+                    logger.warn(format!("Can't answer metrics request: {}", err).as_str());
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+// Writes the textfile for the node exporter, a file that can't be written doesn't change
+// the outcome of the run
+pub fn write_textfile(config: &MetricsConfig) {
+    let Some(file_path) = &config.textfile else {
+        return;
+    };
+
+    if let Err(err) = write_bytes_atomically(file_path, render().as_bytes()) {
+        let logger = crate::logger::Logger::new();
+        logger.error(format!("Can't write metrics file: {}", err.report()).as_str());
+    }
+}
+
+fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    // A client that connects and sends nothing doesn't keep the thread forever
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+
+    let (status, content_type, body) = if path == "/metrics" {
+        ("200 OK", "text/plain; version=0.0.4", render())
+    } else {
+        ("404 Not Found", "text/plain", "Not found\n".to_string())
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+// Prometheus text exposition format
+fn render() -> String {
+    render_registry(&REGISTRY.lock().unwrap())
+}
+
+fn render_registry(registry: &Registry) -> String {
+    let mut result = String::new();
+
+    push_header(&mut result, "rows_total", "counter", "Rows copied per table");
+    for ((category, table), rows) in &registry.rows {
+        push_sample(&mut result, "rows_total", &get_table_labels(category, table), rows);
+    }
+
+    push_header(&mut result, "bytes_written_total", "counter", "Bytes written per table");
+    for ((category, table), bytes) in &registry.bytes {
+        push_sample(&mut result, "bytes_written_total", &get_table_labels(category, table), bytes);
+    }
+
+    push_header(&mut result, "table_rows", "histogram", "Rows copied per table");
+    push_histograms(&mut result, "table_rows", "category", &registry.table_rows);
+
+    push_header(&mut result, "table_bytes", "histogram", "Bytes written per table");
+    push_histograms(&mut result, "table_bytes", "category", &registry.table_bytes);
+
+    push_header(&mut result, "table_duration_seconds", "histogram", "Extract and load time");
+    push_histograms(&mut result, "table_duration_seconds", "phase", &registry.table_durations);
+
+    push_header(&mut result, "query_duration_seconds", "histogram", "Single query latency");
+    push_histograms(&mut result, "query_duration_seconds", "kind", &registry.query_durations);
+
+    push_header(&mut result, "table_failures_total", "counter", "Tables that failed");
+    for (category, failures) in &registry.failures {
+        let labels = format!("category=\"{}\"", escape(category));
+        push_sample(&mut result, "table_failures_total", &labels, failures);
+    }
+
+    push_header(&mut result, "retries_total", "counter", "Retries after a transient error");
+    push_sample(&mut result, "retries_total", "", registry.retries);
+
+    // Only known once the run ends
+    if let Some(run) = &registry.run {
+        let finished_at = run.finished_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        push_header(&mut result, "last_run_success", "gauge", "1 when the last run succeeded");
+        push_sample(&mut result, "last_run_success", "", run.success as u8);
+        push_header(&mut result, "last_run_duration_seconds", "gauge", "Duration of the last run");
+        push_sample(&mut result, "last_run_duration_seconds", "", run.duration.as_secs_f64());
+        push_header(&mut result, "last_run_timestamp_seconds", "gauge", "When the last run ended");
+        push_sample(&mut result, "last_run_timestamp_seconds", "", finished_at);
+    }
+
+    result
+}
+
+fn push_header(result: &mut String, name: &str, metric_type: &str, help: &str) {
+    result.push_str(format!("# HELP {}_{} {}\n", PREFIX, name, help).as_str());
+    result.push_str(format!("# TYPE {}_{} {}\n", PREFIX, name, metric_type).as_str());
+}
+
+fn push_histograms(
+    result: &mut String,
+    name: &str,
+    label_name: &str,
+    histograms: &BTreeMap<String, Histogram>
+) {
+    let bucket_name = format!("{}_bucket", name);
+    for (label, histogram) in histograms {
+        let label = format!("{}=\"{}\"", label_name, escape(label));
+        let mut cumulative = 0;
+        for (bound, observations) in histogram.bounds.iter().zip(&histogram.buckets) {
+            cumulative += observations;
+            let labels = format!("{},le=\"{}\"", label, bound);
+            push_sample(result, &bucket_name, &labels, cumulative);
+        }
+        let labels = format!("{},le=\"+Inf\"", label);
+        push_sample(result, &bucket_name, &labels, histogram.count);
+        push_sample(result, format!("{}_sum", name).as_str(), &label, histogram.sum);
+        push_sample(result, format!("{}_count", name).as_str(), &label, histogram.count);
+    }
+}
+
+fn push_sample<V: Display>(result: &mut String, name: &str, labels: &str, value: V) {
+    if labels.is_empty() {
+        result.push_str(format!("{}_{} {}\n", PREFIX, name, value).as_str());
+    } else {
+        result.push_str(format!("{}_{}{{{}}} {}\n", PREFIX, name, labels, value).as_str());
+    }
+}
+
+fn get_table_labels(category: &str, table: &str) -> String {
+    format!("category=\"{}\",table=\"{}\"", escape(category), escape(table))
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{ escape, observe, render_registry, Registry, DURATION_BUCKETS };
+
+    #[test]
+    fn empty_registry_renders_headers_only() {
+        let result = render_registry(&Registry::new());
+
+        assert!(result.contains("# TYPE batch_data_copy_rows_total counter\n"));
+        assert!(result.contains("batch_data_copy_retries_total 0\n"));
+        assert!(!result.contains("last_run_success"));
+    }
+
+    #[test]
+    fn counters_are_labeled_by_table() {
+        let mut registry = Registry::new();
+        registry.rows.insert(("batch".to_string(), "cb_runs".to_string()), 42);
+
+        let result = render_registry(&registry);
+
+        assert!(
+            result.contains("batch_data_copy_rows_total{category=\"batch\",table=\"cb_runs\"} 42\n")
+        );
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let mut registry = Registry::new();
+        for seconds in [0.02, 0.02, 2.0, 5000.0] {
+            observe(&mut registry.query_durations, "select", &DURATION_BUCKETS, seconds);
+        }
+
+        let result = render_registry(&registry);
+        let name = "batch_data_copy_query_duration_seconds";
+
+        assert!(result.contains(&format!("{}_bucket{{kind=\"select\",le=\"0.01\"}} 0\n", name)));
+        assert!(result.contains(&format!("{}_bucket{{kind=\"select\",le=\"0.05\"}} 2\n", name)));
+        assert!(result.contains(&format!("{}_bucket{{kind=\"select\",le=\"5\"}} 3\n", name)));
+        assert!(result.contains(&format!("{}_bucket{{kind=\"select\",le=\"900\"}} 3\n", name)));
+        assert!(result.contains(&format!("{}_bucket{{kind=\"select\",le=\"+Inf\"}} 4\n", name)));
+        assert!(result.contains(&format!("{}_sum{{kind=\"select\"}} 5002.04\n", name)));
+        assert!(result.contains(&format!("{}_count{{kind=\"select\"}} 4\n", name)));
+    }
+
+    #[test]
+    fn run_result_is_rendered_once_set() {
+        let mut registry = Registry::new();
+        registry.run = Some(super::RunResult {
+            success: true,
+            duration: Duration::from_millis(1500),
+            finished_at: std::time::UNIX_EPOCH + Duration::from_secs(60),
+        });
+
+        let result = render_registry(&registry);
+
+        assert!(result.contains("batch_data_copy_last_run_success 1\n"));
+        assert!(result.contains("batch_data_copy_last_run_duration_seconds 1.5\n"));
+        assert!(result.contains("batch_data_copy_last_run_timestamp_seconds 60\n"));
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -1,8 +1,8 @@
 use mysql::PooledConn;
 
-use crate::{ config::Config, custom_error::CustomResult };
+use crate::{ config::{ Config, SampleRoot }, custom_error::CustomResult };
 
-use super::traits::TableQueryGenerator;
+use super::traits::{ FkColumnUsage, TableQueryGenerator, TableScope };
 
 pub struct BatchTableQueryProvider<'config> {
     pub config: &'config Config,
@@ -15,81 +15,135 @@ impl<'config> BatchTableQueryProvider<'config> {
         table: &String,
         select_column: Option<String>
     ) -> CustomResult<String> {
+        self.get_scoped_select_query(connection, table, select_column, true)
+    }
+
+    // The purge clears every scoped row of the target, the parent subqueries aren't limited either
+    pub fn get_delete_query(
+        &self,
+        connection: &mut PooledConn,
+        table: &String
+    ) -> CustomResult<String> {
+        let scope = self.get_scope(connection, table, false)?;
+
+        Ok(scope.get_delete_query(table))
+    }
+
+    fn get_scoped_select_query(
+        &self,
+        connection: &mut PooledConn,
+        table: &String,
+        select_column: Option<String>,
+        limited: bool
+    ) -> CustomResult<String> {
+        let scope = self.get_scope(connection, table, limited)?;
+
+        Ok(scope.get_select_query(table, select_column))
+    }
+
+    // cb_batch_runs and cb_ tables without references are roots, only they take the limit.
+    // Tables without a scope have no link to the roots, the config rejects a limit with them
+    fn get_scope(
+        &self,
+        connection: &mut PooledConn,
+        table: &String,
+        limited: bool
+    ) -> CustomResult<TableScope> {
         if table == "cb_batch_runs" {
-            self.get_cb_batch_runs_select_query(table, select_column)
+            let mut filter = self.get_cb_batch_runs_filter()?;
+            if let Some(condition) = self.config.sample.get_condition(SampleRoot::BatchRuns, "id") {
+                filter.push_str(format!(" AND {}", condition).as_str());
+            }
+            let limit = self.get_root_limit(connection, table, limited)?;
+            Ok(TableScope { filter, limit })
         } else if table.starts_with("cb_") {
-            self.get_cb_select_query(connection, table, select_column)
+            let references = self.get_table_references(
+                connection,
+                table,
+                &self.config.source.database
+            )?;
+            let filter = self.get_cb_filter(connection, table, &references, limited)?;
+            let limit = if references.is_empty() {
+                self.get_root_limit(connection, table, limited)?
+            } else {
+                None
+            };
+            Ok(TableScope { filter, limit })
         } else {
-            self.get_general_select_query(table)
+            Ok(TableScope { filter: String::new(), limit: None })
         }
     }
 
-    fn get_cb_batch_runs_select_query(
+    fn get_root_limit(
         &self,
-        table: &String,
-        select_column: Option<String>
-    ) -> CustomResult<String> {
-        let selected = self.get_select_column(select_column);
+        connection: &mut PooledConn,
+        table: &str,
+        limited: bool
+    ) -> CustomResult<Option<String>> {
+        if !limited || self.config.business.limit.is_none() || self.config.sample.enabled {
+            return Ok(None);
+        }
+        let columns = self.get_columns(connection, table)?;
+
+        Ok(self.get_limit_clause(table, &columns))
+    }
 
-        let mut query = format!(
-            "SELECT {} FROM {} WHERE study_id = {} AND area_id = {} AND lifecycle_id = {}",
-            selected,
-            table,
+    fn get_cb_batch_runs_filter(&self) -> CustomResult<String> {
+        let mut filter = format!(
+            " WHERE study_id = {} AND area_id = {} AND lifecycle_id = {}",
             self.config.business.study_id,
             self.config.business.area_id,
             self.config.business.lifecycle_id
         );
 
         if let Some(job_id) = self.config.business.job_id {
-            query.push_str(format!(" AND job_id = {}", job_id).as_str());
+            filter.push_str(format!(" AND job_id = {}", job_id).as_str());
         }
 
-        Ok(query)
+        Ok(filter)
     }
 
-    fn get_cb_select_query(
+    fn get_cb_filter(
         &self,
         connection: &mut PooledConn,
-        table: &String,
-        select_column: Option<String>
+        table: &str,
+        references: &[FkColumnUsage],
+        limited: bool
     ) -> CustomResult<String> {
-        let references = self.get_table_references(
-            connection,
-            table,
-            &self.config.source.database
-        )?;
-        let selected = self.get_select_column(select_column);
-        let mut query = format!("SELECT {} FROM {}", selected, table);
+        let mut filter = String::new();
         for (index, reference) in references.iter().enumerate() {
-            let subquery = self.get_select_query(
+            let subquery = self.get_scoped_select_query(
                 connection,
                 &reference.referenced_table_name,
-                Some(reference.referenced_column_name.clone())
+                Some(reference.referenced_column_name.clone()),
+                limited
             )?;
             if index == 0 {
-                query.push_str(
+                filter.push_str(
                     format!("\nWHERE {} IN (\n{}\n)", reference.column_name, subquery).as_str()
                 );
             } else {
-                query.push_str(
+                filter.push_str(
                     format!(" AND {} IN (\n{}\n)", reference.column_name, subquery).as_str()
                 );
             }
         }
 
-        Ok(query)
-    }
+        let sample = &self.config.sample;
+        if let Some(condition) = sample.get_condition(SampleRoot::Subjects, "subject_id") {
+            let columns = self.get_columns(connection, table)?;
+            if columns.iter().any(|column| column.name == "subject_id") {
+                let keyword = if filter.is_empty() { "\nWHERE" } else { " AND" };
+                filter.push_str(format!("{} {}", keyword, condition).as_str());
+            }
+        }
 
-    fn get_general_select_query(&self, table: &String) -> CustomResult<String> {
-        Ok(format!("SELECT * FROM {}", table))
+        Ok(filter)
     }
+}
 
-    fn get_select_column(&self, select_column: Option<String>) -> String {
-        match select_column {
-            Some(column) => column,
-            None => String::from("*"),
-        }
+impl<'config> TableQueryGenerator for BatchTableQueryProvider<'config> {
+    fn get_config(&self) -> &Config {
+        self.config
     }
 }
-
-impl<'config> TableQueryGenerator for BatchTableQueryProvider<'config> {}
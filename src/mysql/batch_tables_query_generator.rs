@@ -1,41 +1,152 @@
+use std::time::Instant;
+
+use mysql::PooledConn;
+
 use crate::{
-    config::Config,
-    custom_error::CustomResult,
+    cancellation::check_cancelled,
+    checkpoint::CheckpointStore,
+    config::{ Config, ConflictMode, UnscopedPurge },
+    custom_error::{ CustomError, CustomResult },
     logger::LoggerTrait,
-    traits::TablesInsertQueryGeneratorTrait,
+    retry::with_retry,
+    summary::{ RunSummary, TableStatus },
+    traits::{ BATCH_TABLES, TableQuery, TablesInsertQueryGeneratorTrait },
+    workers::run_ordered,
 };
 
 use super::{
     batch_table_query_provider::BatchTableQueryProvider,
-    db::get_connection,
-    traits::TableQueryGenerator,
+    db::ConnectionManager,
+    delta::DeltaGenerator,
+    traits::{ StatementTimeoutGuard, TableQueryGenerator },
 };
 
 pub struct BatchTablesQueryGenerator<'config> {
     pub config: &'config Config,
+    pub checkpoint: &'config CheckpointStore,
+    pub summary: &'config RunSummary,
+    pub connections: &'config ConnectionManager,
 }
 
 impl<'config> LoggerTrait for BatchTablesQueryGenerator<'config> {}
 impl<'config> TablesInsertQueryGeneratorTrait for BatchTablesQueryGenerator<'config> {
-    fn generate(&self) -> CustomResult<Option<String>> {
+    fn generate(&self) -> CustomResult<Vec<TableQuery>> {
+        let provider = BatchTableQueryProvider { config: self.config };
+        let results = run_ordered(
+            self.config.extract.workers,
+            &self.config.tables.batch_tables,
+            |table| self.generate_table(&provider, table)
+        );
+
+        results.into_iter().filter_map(Result::transpose).collect()
+    }
+
+    fn generate_purge(&self) -> CustomResult<Vec<TableQuery>> {
         let logger = self.get_logger();
-        let mut result = String::new();
-        let mut connection = get_connection(&self.config.source)?;
-        let provider = BatchTableQueryProvider { config: &self.config };
-        for table in &self.config.tables.batch_tables {
-            let mut select_query = provider.get_select_query(&mut connection, table, None)?;
-            select_query.push_str(";");
-            logger.info(format!("\nselect query:\n\n {}\n\n", select_query).as_str());
-            let data = provider.get_data(&mut connection, table, &select_query)?;
-            let insert_query = provider.generate_insert_query(&data, &table)?;
-            result.push_str(insert_query.as_str());
-            logger.info(format!("\ninsert query:\n\n {}\n\n", insert_query).as_str());
+        let mut result: Vec<TableQuery> = vec![];
+        let mut connection = self.connections.get_connection(&self.config.source)?;
+        let provider = BatchTableQueryProvider { config: self.config };
+        let tables = provider.sort_by_dependencies(
+            &mut connection,
+            &self.config.tables.batch_tables,
+            &self.config.source.database
+        )?;
+        for table in tables.iter().rev() {
+            if !table.starts_with("cb_") {
+                match self.config.insert.unscoped_purge {
+                    UnscopedPurge::Fail => {
+                        return Err(CustomError::UnscopedPurge { table: table.clone() });
+                    }
+                    UnscopedPurge::Skip => {
+                        logger.warn(format!("Table {} has no scope, not purged", table).as_str());
+                        continue;
+                    }
+                    UnscopedPurge::DeleteAll => {
+                        logger.warn(
+                            format!("Table {} has no scope, purge deletes all rows", table).as_str()
+                        );
+                    }
+                }
+            }
+            let delete_query = provider.get_delete_query(&mut connection, table)?;
+            logger.info(format!("\ndelete query:\n\n {}\n\n", delete_query).as_str());
+            result.push(TableQuery {
+                table: table.clone(),
+                query: format!("{};\n", delete_query),
+                rows: 0,
+                conflict_mode: ConflictMode::default(),
+                data: None,
+            });
         }
 
-        if result.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(result))
+        Ok(result)
+    }
+}
+
+impl<'config> BatchTablesQueryGenerator<'config> {
+    fn generate_table(
+        &self,
+        provider: &BatchTableQueryProvider,
+        table: &String
+    ) -> CustomResult<Option<TableQuery>> {
+        let conflict_mode = self.config.insert.get_conflict_mode(table);
+        let extracted = self.checkpoint.get_extracted(BATCH_TABLES, table, conflict_mode);
+        if let Some(table_query) = extracted {
+            self.summary.record(BATCH_TABLES, &table_query.table, TableStatus::Extracted);
+            self.summary.record_checkpoint_rows(BATCH_TABLES, &table_query.table, table_query.rows);
+            return Ok(Some(table_query));
         }
+
+        let operation = format!("Extracting table {}", table);
+        let started = Instant::now();
+        let result = check_cancelled().and_then(|_| {
+            with_retry(&self.config.retry, &operation, |_| {
+                let mut connection = self.connections.acquire_connection(&self.config.source)?;
+                self.extract_table(&mut connection, provider, table, conflict_mode)
+            })
+        });
+        self.summary.record_extract_time(BATCH_TABLES, table, started.elapsed());
+        let result = self.summary.settle_extract(self.config, BATCH_TABLES, table, result)?;
+        let Some(table_query) = result else {
+            return Ok(None);
+        };
+        self.summary.record_rows(BATCH_TABLES, table, table_query.rows);
+        self.checkpoint.mark_extracted(BATCH_TABLES, &table_query)?;
+
+        Ok(Some(table_query))
+    }
+
+    fn extract_table(
+        &self,
+        connection: &mut PooledConn,
+        provider: &BatchTableQueryProvider,
+        table: &String,
+        conflict_mode: ConflictMode
+    ) -> CustomResult<TableQuery> {
+        let logger = self.get_logger();
+        let timeout_ms = self.config.timeout.get_statement_timeout(table);
+        let mut connection = StatementTimeoutGuard::new(connection, timeout_ms)?;
+        let mut select_query = provider.get_select_query(&mut connection, table, None)?;
+        select_query.push(';');
+        logger.info(format!("\nselect query:\n\n {}\n\n", select_query).as_str());
+        self.summary.record_query(BATCH_TABLES, table, &select_query);
+        let data = provider.get_data(&mut connection, table, &select_query)?;
+        let insert_query = if self.config.delta.enabled {
+            let delta = DeltaGenerator { config: self.config, connections: self.connections };
+            delta.generate(table, &select_query, &data, timeout_ms)?
+        } else if self.config.renders_insert_sql() {
+            provider.generate_insert_query(&data.columns, &data.rows, table, conflict_mode)?
+        } else {
+            String::new()
+        };
+        logger.info(format!("\ninsert query:\n\n {}\n\n", insert_query).as_str());
+
+        Ok(TableQuery {
+            table: table.clone(),
+            query: insert_query,
+            rows: data.rows.len(),
+            conflict_mode,
+            data: self.config.keeps_table_data().then_some(data),
+        })
     }
 }
@@ -0,0 +1,249 @@
+use std::{
+    collections::HashSet,
+    fs::{ self, File },
+    io::{ self, Write },
+    path::PathBuf,
+    sync::{ Arc, Mutex },
+};
+
+use mysql::{ prelude::Queryable, Error, LocalInfileHandler, PooledConn, Value };
+
+use crate::{
+    config::{ Config, ConflictMode },
+    custom_error::{ CustomError, CustomResult },
+    files::write_atomically,
+    logger::LoggerTrait,
+};
+
+use super::traits::{ ColumnProps, TableData };
+
+// ER_NOT_ALLOWED_COMMAND and ER_CLIENT_LOCAL_FILES_DISABLED
+const LOCAL_INFILE_REFUSED_CODES: [u16; 2] = [1148, 3948];
+
+pub struct BulkLoader<'config> {
+    pub config: &'config Config,
+    // Canonical paths of the TSV files this loader wrote, the only ones served to the server
+    written_files: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl<'config> LoggerTrait for BulkLoader<'config> {}
+impl<'config> BulkLoader<'config> {
+    pub fn new(config: &'config Config) -> Self {
+        Self { config, written_files: Arc::default() }
+    }
+
+    pub fn get_folder_path(&self) -> String {
+        format!("{}/tsv", self.config.target_path.path)
+    }
+
+    pub fn is_local_infile_enabled(&self, connection: &mut PooledConn) -> bool {
+        let logger = self.get_logger();
+
+        match connection.query_first::<u8, _>("SELECT @@GLOBAL.local_infile") {
+            Ok(Some(1)) => true,
+            Ok(_) => {
+                logger.warn("local_infile is disabled on the target server");
+                false
+            }
+            Err(err) => {
+                logger.warn(format!("Can't read local_infile from the target: {}", err).as_str());
+                false
+            }
+        }
+    }
+
+    // Only serves the files this loader wrote, whatever path the server asks for
+    pub fn get_local_infile_handler(&self) -> LocalInfileHandler {
+        let written_files = Arc::clone(&self.written_files);
+
+        LocalInfileHandler::new(move |file_name, stream| {
+            let requested = String::from_utf8_lossy(file_name).to_string();
+            let file_path = fs::canonicalize(&requested).ok().filter(|file_path| {
+                written_files.lock().unwrap().contains(file_path)
+            });
+            let Some(file_path) = file_path else {
+                return Err(
+                    io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!("{} wasn't written by this load", requested)
+                    )
+                );
+            };
+
+            let mut file = File::open(file_path)?;
+            io::copy(&mut file, stream)?;
+            Ok(())
+        })
+    }
+
+    pub fn write_tsv_file(&self, table: &str, data: &TableData) -> CustomResult<String> {
+        let folder_path = self.get_folder_path();
+        fs::create_dir_all(&folder_path).map_err(|source| CustomError::FolderCreationError {
+            path: folder_path.clone(),
+            source,
+        })?;
+
+        let file_path = format!("{}/{}.tsv", folder_path, table);
+        write_atomically(&file_path, |writer| write_rows(writer, &data.rows))?;
+        let canonical_path = fs::canonicalize(&file_path).map_err(|source| {
+            CustomError::FileCreationError { path: file_path.clone(), source }
+        })?;
+        self.written_files.lock().unwrap().insert(canonical_path);
+
+        Ok(file_path)
+    }
+
+    pub fn get_load_data_query(
+        &self,
+        file_path: &str,
+        table: &str,
+        columns: &[ColumnProps],
+        conflict_mode: ConflictMode
+    ) -> String {
+        // With LOCAL the server treats duplicate keys as warnings even without IGNORE,
+        // Fail is checked with the loaded row count instead
+        let duplicates = match conflict_mode {
+            ConflictMode::Replace => "REPLACE ",
+            ConflictMode::Ignore => "IGNORE ",
+            ConflictMode::Fail | ConflictMode::Upsert => "",
+        };
+        let column_names: Vec<String> = columns
+            .iter()
+            .map(|props| format!("`{}`", props.name))
+            .collect();
+
+        let mut query = format!(
+            "LOAD DATA LOCAL INFILE '{}'\n{}INTO TABLE {}\nCHARACTER SET binary\n",
+            file_path.replace('\\', "\\\\").replace('\'', "\\'"),
+            duplicates,
+            table
+        );
+        query.push_str("FIELDS TERMINATED BY '\\t' ESCAPED BY '\\\\'\nLINES TERMINATED BY '\\n'\n");
+        query.push_str(format!("({});", column_names.join(", ")).as_str());
+
+        query
+    }
+}
+
+pub fn is_local_infile_refused(err: &Error) -> bool {
+    match err {
+        Error::MySqlError(mysql_error) => LOCAL_INFILE_REFUSED_CODES.contains(&mysql_error.code),
+        _ => false,
+    }
+}
+
+fn write_rows<W: Write>(writer: &mut W, rows: &[Vec<Value>]) -> io::Result<()> {
+    for row in rows {
+        for (index, value) in row.iter().enumerate() {
+            if index > 0 {
+                writer.write_all(b"\t")?;
+            }
+            write_value(writer, value)?;
+        }
+        writer.write_all(b"\n")?;
+    }
+
+    writer.flush()
+}
+
+fn write_value<W: Write>(writer: &mut W, value: &Value) -> io::Result<()> {
+    match value {
+        Value::NULL => writer.write_all(b"\\N"),
+        Value::Bytes(bytes) => write_escaped(writer, bytes),
+        Value::Int(value) => write!(writer, "{}", value),
+        Value::UInt(value) => write!(writer, "{}", value),
+        Value::Float(value) => write!(writer, "{}", value),
+        Value::Double(value) => write!(writer, "{}", value),
+        Value::Date(year, month, day, hour, minute, second, micros) =>
+            write!(
+                writer,
+                "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:06}",
+                year,
+                month,
+                day,
+                hour,
+                minute,
+                second,
+                micros
+            ),
+        Value::Time(negative, days, hours, minutes, seconds, micros) =>
+            write!(
+                writer,
+                "{}{:02}:{:02}:{:02}.{:06}",
+                if *negative { "-" } else { "" },
+                *days * 24 + (*hours as u32),
+                minutes,
+                seconds,
+                micros
+            ),
+    }
+}
+
+fn write_escaped<W: Write>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    for byte in bytes {
+        match byte {
+            b'\\' => writer.write_all(b"\\\\")?,
+            b'\t' => writer.write_all(b"\\t")?,
+            b'\n' => writer.write_all(b"\\n")?,
+            b'\r' => writer.write_all(b"\\r")?,
+            0 => writer.write_all(b"\\0")?,
+            _ => writer.write_all(&[*byte])?,
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use mysql::Value;
+
+    use super::{ write_rows, write_value };
+
+    fn render(value: Value) -> String {
+        let mut buffer: Vec<u8> = vec![];
+        write_value(&mut buffer, &value).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn null_is_written_as_marker() {
+        assert_eq!(render(Value::NULL), "\\N");
+    }
+
+    #[test]
+    fn separators_are_escaped() {
+        assert_eq!(render(Value::Bytes(b"a\tb".to_vec())), "a\\tb");
+        assert_eq!(render(Value::Bytes(b"a\nb".to_vec())), "a\\nb");
+        assert_eq!(render(Value::Bytes(b"a\r\nb".to_vec())), "a\\r\\nb");
+    }
+
+    #[test]
+    fn backslash_is_escaped() {
+        assert_eq!(render(Value::Bytes(b"C:\\tmp".to_vec())), "C:\\\\tmp");
+        // A literal \N in the data must not be read back as NULL
+        assert_eq!(render(Value::Bytes(b"\\N".to_vec())), "\\\\N");
+    }
+
+    #[test]
+    fn zero_byte_is_escaped() {
+        assert_eq!(render(Value::Bytes(vec![b'a', 0, b'b'])), "a\\0b");
+    }
+
+    #[test]
+    fn other_bytes_are_kept() {
+        assert_eq!(render(Value::Bytes("plain 'text' é".as_bytes().to_vec())), "plain 'text' é");
+    }
+
+    #[test]
+    fn rows_are_tab_separated_lines() {
+        let rows = vec![
+            vec![Value::Int(1), Value::NULL],
+            vec![Value::Int(2), Value::Bytes(b"x".to_vec())]
+        ];
+        let mut buffer: Vec<u8> = vec![];
+        write_rows(&mut buffer, &rows).unwrap();
+
+        assert_eq!(String::from_utf8(buffer).unwrap(), "1\t\\N\n2\tx\n");
+    }
+}
@@ -1,70 +1,642 @@
-use mysql::{ prelude::Queryable, PooledConn };
+use std::{ collections::HashSet, fs, time::Instant };
+
+use mysql::{ prelude::Queryable, PooledConn, Transaction, TxOpts, Value };
 
 use crate::{
-    config::{ Config, DbConfig },
+    cancellation::check_cancelled,
+    checkpoint::CheckpointStore,
+    config::{ Config, ConflictMode, DbConfig, LoadMethod, LoadMode },
     custom_error::{ CustomError, CustomResult },
     logger::LoggerTrait,
-    traits::{ DataSaverTrait, InsertQueries },
+    metrics,
+    retry::with_retry,
+    summary::{ RunSummary, TableStatus },
+    traits::{
+        DataSaverTrait,
+        InsertQueries,
+        TableQuery,
+        BATCH_TABLES,
+        DOUBLE_STAGED_TABLES,
+        PURGE_TABLES,
+        TRIPLE_STAGED_TABLES,
+    },
+};
+
+use super::{
+    bulk_loader::{ is_local_infile_refused, BulkLoader },
+    db::ConnectionManager,
+    traits::{ TableData, TableQueryGenerator },
 };
 
-use super::db::get_connection;
+// Prepared statements are limited to 65535 placeholders
+const MAX_PLACEHOLDERS: usize = 65535;
 
 pub struct DataSaver<'config> {
     pub config: &'config Config,
+    pub checkpoint: &'config CheckpointStore,
+    pub summary: &'config RunSummary,
+    pub connections: &'config ConnectionManager,
 }
 
 impl<'config> LoggerTrait for DataSaver<'config> {}
 impl<'config> DataSaverTrait for DataSaver<'config> {
     fn save(&self, data: &InsertQueries) -> CustomResult<()> {
-        self.save_to_files(data, &self.config.target_path.path)?;
+        if self.config.target_path.write_files {
+            self.save_to_files(data, &self.config.target_path.path)?;
+            self.summary.record_file_bytes(data);
+        }
 
-        if let Some(target_db) = &self.config.target_db {
-            self.save_to_db(data, &target_db)?;
+        match &self.config.target_db {
+            Some(target_db) => self.save_to_db(data, target_db)?,
+            None if !self.config.target_path.write_files => {
+                self.get_logger().warn("Neither output files nor a target DB are configured");
+            }
+            None => {}
         }
 
         Ok(())
     }
 
     fn save_to_db(&self, data: &InsertQueries, config: &DbConfig) -> CustomResult<()> {
-        let mut connection = get_connection(config)?;
-        let logger = self.get_logger();
+        match self.config.load.mode {
+            // Statements already committed can't be told apart from the failed one,
+            // so autocommit loads are never retried
+            LoadMode::Autocommit => {
+                let mut connection = self.connections.get_connection(config)?;
+                self.load(&mut connection, data)
+            }
+            // The retry covers the connection too, it isn't retried on its own
+            LoadMode::Transaction | LoadMode::Savepoint => {
+                with_retry(&self.config.retry, "Target load", |_| {
+                    let mut connection = self.connections.acquire_connection(config)?;
+                    self.load(&mut connection, data)
+                })
+            }
+        }
+    }
+}
 
-        self.exec_no_output_statement(&mut connection, &"SET FOREIGN_KEY_CHECKS = 0".to_string())?;
-        if let Some(batch_sql) = &data.batch_tables {
-            logger.info("Executing batch tables");
-            self.exec_no_output_statement(&mut connection, batch_sql)?;
-            logger.info("Batch tables executed");
+type Categories<'data> = [(&'static str, &'data Vec<TableQuery>)];
+
+impl<'config> TableQueryGenerator for DataSaver<'config> {
+    fn get_config(&self) -> &Config {
+        self.config
+    }
+}
+impl DataSaver<'_> {
+    fn load(&self, connection: &mut PooledConn, data: &InsertQueries) -> CustomResult<()> {
+        let session = ForeignKeyChecksGuard::new(connection)?;
+        let loader = self.prepare_load_data(session.connection);
+        let categories = [
+            (PURGE_TABLES, &data.purge_tables),
+            (BATCH_TABLES, &data.batch_tables),
+            (DOUBLE_STAGED_TABLES, &data.double_staged_tables),
+            (TRIPLE_STAGED_TABLES, &data.triple_staged_tables),
+        ];
+
+        let result = match self.config.load.mode {
+            LoadMode::Autocommit =>
+                self.load_autocommit(session.connection, &categories, loader.as_ref()),
+            LoadMode::Transaction =>
+                self.load_in_transaction(session.connection, &categories, loader.as_ref()),
+            LoadMode::Savepoint =>
+                self.load_with_savepoints(session.connection, &categories, loader.as_ref()),
+        };
+        // The pooled connection must not serve files to whoever borrows it next
+        if loader.is_some() {
+            session.connection.set_local_infile_handler(None);
         }
 
-        if let Some(double_staged_sql) = &data.double_staged_tables {
-            logger.info("Executing double staged tables");
-            self.exec_no_output_statement(&mut connection, double_staged_sql)?;
-            logger.info("Double staged tables executed");
+        result
+    }
+
+    // Returns the loader when tables can be bulk loaded, otherwise they go through
+    // batched INSERTs
+    fn prepare_load_data(&self, connection: &mut PooledConn) -> Option<BulkLoader<'_>> {
+        if self.config.load.method != LoadMethod::LoadData {
+            return None;
         }
 
-        if let Some(triple_staged_sql) = &data.triple_staged_tables {
-            logger.info("Executing triple staged tables");
-            self.exec_no_output_statement(&mut connection, triple_staged_sql)?;
-            logger.info("Triple staged tables executed");
+        let loader = BulkLoader::new(self.config);
+        if !loader.is_local_infile_enabled(connection) {
+            self.get_logger().warn("Falling back to batched INSERTs");
+            return None;
         }
 
-        self.exec_no_output_statement(&mut connection, &"SET FOREIGN_KEY_CHECKS = 1".to_string())?;
+        connection.set_local_infile_handler(Some(loader.get_local_infile_handler()));
+        Some(loader)
+    }
+
+    fn load_autocommit(
+        &self,
+        connection: &mut PooledConn,
+        categories: &Categories,
+        loader: Option<&BulkLoader>
+    ) -> CustomResult<()> {
+        let logger = self.get_logger();
+
+        for (category, queries) in categories {
+            if queries.is_empty() {
+                continue;
+            }
+            logger.info(format!("Executing {} tables", category).as_str());
+            for table_query in self.get_pending(category, queries) {
+                let result = check_cancelled().and_then(|_| {
+                    self.exec_table_query(connection, category, table_query, loader)
+                });
+                match result {
+                    Ok(_) => self.mark_loaded(&[(category, table_query)])?,
+                    Err(err) => {
+                        let table = &table_query.table;
+                        self.summary.record_failure(category, table, &err);
+                        self.summary.continue_after(self.config, category, table, err)?;
+                    }
+                }
+            }
+            logger.info(format!("{} tables executed", category).as_str());
+        }
 
         Ok(())
     }
-}
 
-impl DataSaver<'_> {
-    fn exec_no_output_statement(
+    fn load_in_transaction(
+        &self,
+        connection: &mut PooledConn,
+        categories: &Categories,
+        loader: Option<&BulkLoader>
+    ) -> CustomResult<()> {
+        let logger = self.get_logger();
+        let mut loaded: Vec<(&str, &TableQuery)> = vec![];
+        let mut transaction = connection
+            .start_transaction(TxOpts::default())
+            .map_err(CustomError::from)?;
+
+        for (category, queries) in categories {
+            if queries.is_empty() {
+                continue;
+            }
+            logger.info(format!("Executing {} tables", category).as_str());
+            for table_query in self.get_pending(category, queries) {
+                let result = check_cancelled().and_then(|_| {
+                    self.exec_table_query(&mut transaction, category, table_query, loader)
+                });
+                if let Err(err) = result {
+                    self.summary.record_failure(category, &table_query.table, &err);
+                    self.rollback(transaction);
+                    return Err(err);
+                }
+                loaded.push((category, table_query));
+            }
+            logger.info(format!("{} tables executed", category).as_str());
+        }
+
+        transaction.commit().map_err(CustomError::from)?;
+        logger.info("Target load committed");
+
+        self.mark_loaded(&loaded)
+    }
+
+    fn load_with_savepoints(
         &self,
         connection: &mut PooledConn,
-        query: &String
+        categories: &Categories,
+        loader: Option<&BulkLoader>
     ) -> CustomResult<()> {
-        let result = connection.query_drop(query);
+        let logger = self.get_logger();
+        let mut failures: Vec<CustomError> = vec![];
+        let mut loaded: Vec<(&str, &TableQuery)> = vec![];
+        let purges: Vec<&TableQuery> = categories
+            .iter()
+            .filter(|(category, _)| *category == PURGE_TABLES)
+            .flat_map(|(category, queries)| self.get_pending(category, queries))
+            .collect();
+        let mut purges = PurgeQueue::new(purges);
+        let mut transaction = connection
+            .start_transaction(TxOpts::default())
+            .map_err(CustomError::from)?;
+
+        for (category, queries) in categories {
+            if queries.is_empty() || *category == PURGE_TABLES {
+                continue;
+            }
+            logger.info(format!("Executing {} tables", category).as_str());
+            for table_query in self.get_pending(category, queries) {
+                let table = table_query.table.as_str();
+                if let Err(err) = check_cancelled() {
+                    self.summary.record_failure(category, table, &err);
+                    self.rollback(transaction);
+                    return Err(err);
+                }
 
+                let due = purges.get_due(table);
+                let mut statements: Vec<(&str, &TableQuery)> = due
+                    .iter()
+                    .map(|purge| (PURGE_TABLES, *purge))
+                    .collect();
+                statements.push((category, table_query));
+                match self.exec_in_savepoint(&mut transaction, &statements, loader)? {
+                    Ok(_) => {
+                        purges.settle(&due);
+                        loaded.extend(statements);
+                    }
+                    Err(err) => {
+                        // The table keeps its rows, its purge never runs
+                        purges.skip(table);
+                        let message = format!("Table {} rolled back to its savepoint", table);
+                        logger.warn(message.as_str());
+                        self.summary.record_failure(category, table, &err);
+                        failures.push(err);
+                    }
+                }
+            }
+            logger.info(format!("{} tables executed", category).as_str());
+        }
+
+        // Purged tables without anything to load, they have no children left to purge
+        for purge in purges.get_remaining() {
+            let statements = [(PURGE_TABLES, purge)];
+            match self.exec_in_savepoint(&mut transaction, &statements, loader)? {
+                Ok(_) => loaded.extend(statements),
+                Err(err) => {
+                    self.summary.record_failure(PURGE_TABLES, &purge.table, &err);
+                    failures.push(err);
+                }
+            }
+        }
+
+        transaction.commit().map_err(CustomError::from)?;
+        logger.info("Target load committed");
+        self.mark_loaded(&loaded)?;
+
+        if failures.is_empty() || self.config.errors.continue_on_error {
+            Ok(())
+        } else {
+            Err(CustomError::TablesFailed(failures))
+        }
+    }
+
+    // The outer error means the transaction itself is gone, the inner one that the
+    // statements failed and were rolled back to the savepoint
+    fn exec_in_savepoint(
+        &self,
+        transaction: &mut Transaction,
+        statements: &[(&str, &TableQuery)],
+        loader: Option<&BulkLoader>
+    ) -> CustomResult<CustomResult<()>> {
+        self.exec_statement(transaction, "SAVEPOINT table_load")?;
+        let result = statements.iter().try_for_each(|(category, table_query)| {
+            self.exec_table_query(transaction, category, table_query, loader)
+        });
         match result {
+            Ok(_) => self.exec_statement(transaction, "RELEASE SAVEPOINT table_load")?,
+            // Fails when the server already rolled back the whole transaction,
+            // e.g. on a deadlock, in which case nothing is left to commit
+            Err(_) => self.exec_statement(transaction, "ROLLBACK TO SAVEPOINT table_load")?,
+        }
+
+        Ok(result)
+    }
+
+    fn rollback(&self, transaction: Transaction) {
+        let logger = self.get_logger();
+
+        match transaction.rollback() {
+            Ok(_) => logger.warn("Target load rolled back"),
+            Err(err) => {
+                logger.error(format!("Can't roll back target load: {}", err).as_str());
+            }
+        }
+    }
+
+    // Skips the tables a resumed run already loaded
+    fn get_pending<'data>(
+        &self,
+        category: &str,
+        queries: &'data [TableQuery]
+    ) -> Vec<&'data TableQuery> {
+        let logger = self.get_logger();
+
+        queries
+            .iter()
+            .filter(|table_query| {
+                let loaded = self.checkpoint.is_loaded(category, &table_query.table);
+                if loaded {
+                    logger.info(format!("Table {} is already loaded", table_query.table).as_str());
+                    self.summary.record(category, &table_query.table, TableStatus::Loaded);
+                }
+                !loaded
+            })
+            .collect()
+    }
+
+    fn mark_loaded(&self, loaded: &[(&str, &TableQuery)]) -> CustomResult<()> {
+        for (category, table_query) in loaded {
+            self.checkpoint.mark_loaded(category, &table_query.table)?;
+            self.summary.record(category, &table_query.table, TableStatus::Loaded);
+        }
+
+        Ok(())
+    }
+
+    fn exec_table_query<Q: Queryable>(
+        &self,
+        connection: &mut Q,
+        category: &str,
+        table_query: &TableQuery,
+        loader: Option<&BulkLoader>
+    ) -> CustomResult<()> {
+        let logger = self.get_logger();
+        if table_query.is_empty() {
+            logger.debug(format!("No rows to load for {} table", table_query.table).as_str());
+            return Ok(());
+        }
+        logger.debug(format!("Loading {} table {}", category, table_query.table).as_str());
+        let started = Instant::now();
+
+        let result = match &table_query.data {
+            Some(data) if self.config.load.method == LoadMethod::Direct => {
+                self.exec_prepared_inserts(connection, category, table_query, data)
+            }
+            // LOAD DATA has no equivalent of ON DUPLICATE KEY UPDATE
+            Some(data) if table_query.conflict_mode != ConflictMode::Upsert => {
+                match loader {
+                    Some(loader) => {
+                        self.exec_load_data(connection, loader, category, table_query, data)
+                    }
+                    None => self.exec_batched_inserts(connection, category, table_query, data),
+                }
+            }
+            _ => {
+                self.exec_load_statement(
+                    connection,
+                    category,
+                    &table_query.table,
+                    &table_query.query
+                )
+            }
+        };
+        self.summary.record_load_time(category, &table_query.table, started.elapsed());
+
+        result
+    }
+
+    fn exec_load_data<Q: Queryable>(
+        &self,
+        connection: &mut Q,
+        loader: &BulkLoader,
+        category: &str,
+        table_query: &TableQuery,
+        data: &TableData
+    ) -> CustomResult<()> {
+        let file_path = loader.write_tsv_file(&table_query.table, data)?;
+        if let Ok(metadata) = fs::metadata(&file_path) {
+            self.summary.record_bytes(category, &table_query.table, metadata.len());
+        }
+        let query = loader.get_load_data_query(
+            &file_path,
+            &table_query.table,
+            &data.columns,
+            table_query.conflict_mode
+        );
+
+        let started = Instant::now();
+        let loaded_rows = connection.query_iter(&query).map(|result| result.affected_rows());
+        metrics::observe_query("load", started.elapsed());
+        match loaded_rows {
+            // Duplicate keys only skip rows with LOCAL, Fail has to catch them here
+            Ok(loaded_rows) if table_query.conflict_mode == ConflictMode::Fail => {
+                let skipped = data.rows.len().saturating_sub(loaded_rows as usize);
+                if skipped > 0 {
+                    return Err(CustomError::LoadDataSkippedRows {
+                        table: table_query.table.clone(),
+                        skipped,
+                    });
+                }
+                Ok(())
+            }
             Ok(_) => Ok(()),
-            Err(err) => Err(CustomError::DbQueryExecution(err.to_string())),
+            Err(err) if is_local_infile_refused(&err) => {
+                let message = format!(
+                    "Target refused LOAD DATA LOCAL INFILE for {}, using batched INSERTs",
+                    table_query.table
+                );
+                self.get_logger().warn(message.as_str());
+                self.summary.record_warning(category, &table_query.table, &message);
+                self.exec_batched_inserts(connection, category, table_query, data)
+            }
+            Err(err) => Err(self.get_load_error(category, &table_query.table, &query, err)),
+        }
+    }
+
+    fn exec_batched_inserts<Q: Queryable>(
+        &self,
+        connection: &mut Q,
+        category: &str,
+        table_query: &TableQuery,
+        data: &TableData
+    ) -> CustomResult<()> {
+        for rows in data.rows.chunks(self.config.load.batch_size.max(1)) {
+            let query = self.generate_insert_query(
+                &data.columns,
+                rows,
+                &table_query.table,
+                table_query.conflict_mode
+            )?;
+            self.exec_load_statement(connection, category, &table_query.table, &query)?;
+        }
+
+        Ok(())
+    }
+
+    fn exec_prepared_inserts<Q: Queryable>(
+        &self,
+        connection: &mut Q,
+        category: &str,
+        table_query: &TableQuery,
+        data: &TableData
+    ) -> CustomResult<()> {
+        let column_count = data.columns.len().max(1);
+        let batch_size = self.config.load.batch_size.clamp(1, MAX_PLACEHOLDERS / column_count);
+
+        for rows in data.rows.chunks(batch_size) {
+            // Full batches share the query text, so the connection's statement cache
+            // prepares it only once per table
+            let query = self.get_prepared_insert_query(
+                &data.columns,
+                rows.len(),
+                &table_query.table,
+                table_query.conflict_mode
+            );
+            let params: Vec<Value> = rows.iter().flatten().cloned().collect();
+
+            let started = Instant::now();
+            connection
+                .exec_drop(&query, params)
+                .map_err(|err| self.get_load_error(category, &table_query.table, &query, err))?;
+            metrics::observe_query("load", started.elapsed());
+        }
+
+        Ok(())
+    }
+
+    fn exec_load_statement<Q: Queryable>(
+        &self,
+        connection: &mut Q,
+        category: &str,
+        table: &str,
+        query: &str
+    ) -> CustomResult<()> {
+        let started = Instant::now();
+        connection
+            .query_drop(query)
+            .map_err(|err| self.get_load_error(category, table, query, err))?;
+        metrics::observe_query("load", started.elapsed());
+
+        Ok(())
+    }
+
+    fn get_load_error(
+        &self,
+        category: &str,
+        table: &str,
+        query: &str,
+        err: mysql::Error
+    ) -> CustomError {
+        let err = CustomError::from(err).with_table(table).with_sql(query);
+        let message = format!("Loading {} table failed: {}", category, err.report());
+        self.get_logger().error(message.as_str());
+
+        err
+    }
+
+    fn exec_statement<Q: Queryable>(&self, connection: &mut Q, query: &str) -> CustomResult<()> {
+        connection.query_drop(query).map_err(CustomError::from)
+    }
+}
+
+// Purges in reverse dependency order, children first. A table is purged under the
+// savepoint of its load, together with every purge listed before its own that hasn't run
+// yet, so a child's scope is still matched against the parent rows it was extracted with
+struct PurgeQueue<'data> {
+    purges: Vec<&'data TableQuery>,
+    // Tables already purged, or never to be purged because their load failed
+    settled: HashSet<String>,
+}
+
+impl<'data> PurgeQueue<'data> {
+    fn new(purges: Vec<&'data TableQuery>) -> Self {
+        Self { purges, settled: HashSet::new() }
+    }
+
+    // Nothing when the table has no purge of its own
+    fn get_due(&self, table: &str) -> Vec<&'data TableQuery> {
+        let Some(position) = self.purges.iter().position(|purge| purge.table == table) else {
+            return vec![];
+        };
+
+        self.purges[..=position]
+            .iter()
+            .filter(|purge| !self.settled.contains(&purge.table))
+            .copied()
+            .collect()
+    }
+
+    fn settle(&mut self, purges: &[&TableQuery]) {
+        self.settled.extend(purges.iter().map(|purge| purge.table.clone()));
+    }
+
+    fn skip(&mut self, table: &str) {
+        self.settled.insert(table.to_string());
+    }
+
+    fn get_remaining(&self) -> Vec<&'data TableQuery> {
+        self.purges
+            .iter()
+            .filter(|purge| !self.settled.contains(&purge.table))
+            .copied()
+            .collect()
+    }
+}
+
+// Disables foreign key checks for the target session and restores them on drop,
+// so the pooled connection never goes back with checks off, whichever way the load ends.
+struct ForeignKeyChecksGuard<'conn> {
+    connection: &'conn mut PooledConn,
+}
+
+impl<'conn> ForeignKeyChecksGuard<'conn> {
+    fn new(connection: &'conn mut PooledConn) -> CustomResult<Self> {
+        connection
+            .query_drop("SET FOREIGN_KEY_CHECKS = 0")
+            .map_err(CustomError::from)?;
+
+        Ok(Self { connection })
+    }
+}
+
+impl Drop for ForeignKeyChecksGuard<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = self.connection.query_drop("SET FOREIGN_KEY_CHECKS = 1") {
+            let logger = crate::logger::Logger::new();
+            logger.error(format!("Can't restore FOREIGN_KEY_CHECKS: {}", err).as_str());
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{ config::ConflictMode, traits::TableQuery };
+
+    use super::PurgeQueue;
+
+    fn get_purge(table: &str) -> TableQuery {
+        TableQuery {
+            table: table.to_string(),
+            query: format!("DELETE FROM {};\n", table),
+            rows: 0,
+            conflict_mode: ConflictMode::Fail,
+            data: None,
+        }
+    }
+
+    fn get_tables(purges: &[&TableQuery]) -> Vec<String> {
+        purges
+            .iter()
+            .map(|purge| purge.table.clone())
+            .collect()
+    }
+
+    #[test]
+    fn parent_load_purges_its_child_first() {
+        let (child, parent) = (get_purge("cb_jobs"), get_purge("cb_batch_runs"));
+        let mut queue = PurgeQueue::new(vec![&child, &parent]);
+
+        // Parents load first, the child is purged before the parent rows it matches are gone
+        let due = queue.get_due("cb_batch_runs");
+        assert_eq!(get_tables(&due), vec!["cb_jobs", "cb_batch_runs"]);
+        queue.settle(&due);
+
+        assert!(queue.get_due("cb_jobs").is_empty());
+        assert!(queue.get_remaining().is_empty());
+    }
+
+    #[test]
+    fn failed_parent_is_not_purged_later() {
+        let (child, parent) = (get_purge("cb_jobs"), get_purge("cb_batch_runs"));
+        let mut queue = PurgeQueue::new(vec![&child, &parent]);
+
+        queue.skip("cb_batch_runs");
+
+        assert_eq!(get_tables(&queue.get_due("cb_jobs")), vec!["cb_jobs"]);
+        assert_eq!(get_tables(&queue.get_remaining()), vec!["cb_jobs"]);
+    }
+
+    #[test]
+    fn table_without_purge_has_nothing_due() {
+        let child = get_purge("cb_jobs");
+        let queue = PurgeQueue::new(vec![&child]);
+
+        assert!(queue.get_due("cb_batch_runs").is_empty());
+        assert_eq!(get_tables(&queue.get_remaining()), vec!["cb_jobs"]);
+    }
+}
@@ -1,43 +1,128 @@
+use std::{ collections::HashMap, sync::Mutex, time::Duration };
+
 use mysql::*;
 
-use crate::config::DbConfig;
+use crate::config::{ Config, DbConfig, PoolConfig, RetryConfig };
 use crate::custom_error::{ CustomResult, CustomError };
+use crate::logger::LoggerTrait;
+use crate::retry::{ is_transient_mysql_error, with_retry };
 
-pub fn get_connection(db_config: &DbConfig) -> CustomResult<PooledConn> {
-    let pool = get_connections_pool(db_config)?;
-    let logger = crate::logger::Logger::new();
+// Owns one pool per database for the whole run, pools are created on first use.
+// Keeps its own copy of the settings, so it outlives the sampling that fills in the config
+pub struct ConnectionManager {
+    retry: RetryConfig,
+    pool: PoolConfig,
+    pools: Mutex<HashMap<String, Pool>>,
+}
 
-    let connection = match pool.get_conn() {
-        Ok(conn) => {
-            logger.warn("Got connection from Pool");
-            conn
+impl LoggerTrait for ConnectionManager {}
+impl ConnectionManager {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            retry: config.retry.clone(),
+            pool: config.pool.clone(),
+            pools: Mutex::new(HashMap::new()),
         }
-        Err(err) => {
-            logger.error(format!("Can't get connection from Pool: {:#?}", err).as_str());
-            return Err(CustomError::DbConnection);
+    }
+
+    // Retries on its own, for callers that aren't already inside a retry loop
+    pub fn get_connection(&self, db_config: &DbConfig) -> CustomResult<PooledConn> {
+        with_retry(&self.retry, "Connecting to MySQL", |_| self.acquire_connection(db_config))
+    }
+
+    // Single attempt, a failed connection is retried by the loop around the whole operation
+    pub fn acquire_connection(&self, db_config: &DbConfig) -> CustomResult<PooledConn> {
+        let pool = self.get_pool(db_config)?;
+        let logger = self.get_logger();
+
+        let connection = match pool.get_conn() {
+            Ok(conn) => {
+                logger.warn("Got connection from Pool");
+                conn
+            }
+            Err(err) => {
+                logger.error(format!("Can't get connection from Pool: {:#?}", err).as_str());
+                return Err(get_connection_error(db_config, err));
+            }
+        };
+
+        Ok(connection)
+    }
+
+    fn get_pool(&self, db_config: &DbConfig) -> CustomResult<Pool> {
+        let key = get_url(db_config);
+        let mut pools = self.pools.lock().unwrap();
+        if let Some(pool) = pools.get(&key) {
+            return Ok(pool.clone());
         }
-    };
 
-    Ok(connection)
+        let pool = get_connections_pool(db_config, &self.pool)?;
+        pools.insert(key, pool.clone());
+
+        Ok(pool)
+    }
 }
 
-pub fn get_connections_pool(db_config: &DbConfig) -> CustomResult<Pool> {
+fn get_connections_pool(db_config: &DbConfig, pool_config: &PoolConfig) -> CustomResult<Pool> {
     let logger = crate::logger::Logger::new();
-    let url = get_url(db_config);
-    let pool = Pool::new(url.as_str());
+    let opts = get_opts(db_config, pool_config)?;
+    let pool = Pool::new(opts);
 
     match pool {
         Ok(pool) => {
-            logger.info("Created connection Pool for DB");
+            logger.info(
+                format!(
+                    "Created connection Pool for DB {} ({}-{} connections)",
+                    db_config.database,
+                    pool_config.min_connections,
+                    pool_config.max_connections
+                ).as_str()
+            );
             Ok(pool)
         }
         Err(err) => {
             logger.error(format!("Can't create connection Pool: {:#?}", err).as_str());
-            Err(CustomError::DbConnection)
+            Err(get_connection_error(db_config, err))
         }
     }
 }
 
+fn get_opts(db_config: &DbConfig, pool_config: &PoolConfig) -> CustomResult<OptsBuilder> {
+    let logger = crate::logger::Logger::new();
+    let opts = Opts::from_url(get_url(db_config).as_str()).map_err(|err| {
+        logger.error(format!("Invalid connection url: {:#?}", err).as_str());
+        CustomError::DbConnection { database: db_config.database.clone(), source: Box::new(err) }
+    })?;
+
+    let constraints = PoolConstraints::new(
+        pool_config.min_connections,
+        pool_config.max_connections
+    ).ok_or_else(|| {
+        let message = "Pool min_connections can't be greater than max_connections";
+        logger.error(message);
+        CustomError::DbConnection { database: db_config.database.clone(), source: message.into() }
+    })?;
+    let pool_opts = PoolOpts::default()
+        .with_constraints(constraints)
+        .with_check_health(pool_config.check_health);
+
+    Ok(
+        OptsBuilder::from_opts(opts)
+            .pool_opts(pool_opts)
+            .tcp_connect_timeout(Some(Duration::from_millis(pool_config.connect_timeout_ms)))
+            .read_timeout(pool_config.read_timeout_ms.map(Duration::from_millis))
+            .write_timeout(pool_config.write_timeout_ms.map(Duration::from_millis))
+    )
+}
+
+fn get_connection_error(db_config: &DbConfig, err: Error) -> CustomError {
+    if is_transient_mysql_error(&err) {
+        CustomError::from(err)
+    } else {
+        CustomError::DbConnection { database: db_config.database.clone(), source: Box::new(err) }
+    }
+}
+
 fn get_url(db_config: &DbConfig) -> String {
     let url = format!(
         "mysql://{}:{}@{}:{}/{}",
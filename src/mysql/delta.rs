@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+
+use mysql::Value;
+
+use crate::{
+    config::{ Config, ConflictMode },
+    custom_error::{ CustomError, CustomResult },
+    logger::LoggerTrait,
+    traits::decode_or_null,
+};
+
+use super::{
+    db::ConnectionManager,
+    traits::{
+        get_decoding_error,
+        ColumnProps,
+        StatementTimeoutGuard,
+        TableData,
+        TableQueryGenerator,
+    },
+};
+
+// Turns the difference between the scoped source rows and the same scope in the target
+// into the DELETEs, UPDATEs and INSERTs that bring the target in line
+pub struct DeltaGenerator<'config> {
+    pub config: &'config Config,
+    pub connections: &'config ConnectionManager,
+}
+
+impl<'config> LoggerTrait for DeltaGenerator<'config> {}
+impl<'config> TableQueryGenerator for DeltaGenerator<'config> {
+    fn get_config(&self) -> &Config {
+        self.config
+    }
+}
+
+impl DeltaGenerator<'_> {
+    pub fn generate(
+        &self,
+        table: &str,
+        select_query: &str,
+        source: &TableData,
+        timeout_ms: Option<u64>
+    ) -> CustomResult<String> {
+        let Some(target_db) = &self.config.target_db else {
+            return Err(CustomError::DeltaWithoutTarget);
+        };
+        let key_positions: Vec<usize> = source.columns
+            .iter()
+            .enumerate()
+            .filter(|(_, props)| props.key == "PRI")
+            .map(|(position, _)| position)
+            .collect();
+        if key_positions.is_empty() {
+            return Err(CustomError::MissingPrimaryKey { table: table.to_string() });
+        }
+
+        // Runs inside the extract retry, which also covers the connection
+        let mut connection = self.connections.acquire_connection(target_db)?;
+        let target = {
+            let mut connection = StatementTimeoutGuard::new(&mut connection, timeout_ms)?;
+            self.get_data(&mut connection, table, select_query)?
+        };
+
+        self.get_delta_query(table, source, &key_positions, target)
+    }
+
+    fn get_delta_query(
+        &self,
+        table: &str,
+        source: &TableData,
+        key_positions: &[usize],
+        target: TableData
+    ) -> CustomResult<String> {
+        let mut target_rows = self.get_target_rows(table, &source.columns, key_positions, target)?;
+
+        let mut inserts: Vec<Vec<Value>> = vec![];
+        let mut updates: Vec<String> = vec![];
+        let columns = &source.columns;
+        for (row_index, row) in source.rows.iter().enumerate() {
+            let key = self.get_key_predicate(table, columns, key_positions, row_index, row)?;
+            match target_rows.remove(&key) {
+                None => inserts.push(row.clone()),
+                Some(target_row) if target_row != *row => {
+                    let update = self.get_update_query(
+                        table,
+                        columns,
+                        row_index,
+                        row,
+                        &target_row,
+                        &key
+                    )?;
+                    updates.push(update);
+                }
+                Some(_) => {}
+            }
+        }
+        let mut deletes: Vec<String> = if self.config.deletes_missing_rows() {
+            target_rows.into_keys().collect()
+        } else {
+            if self.config.delta.delete_missing && !target_rows.is_empty() {
+                let message = format!(
+                    "Table {}: {} target rows not deleted, the extract is limited or sampled",
+                    table,
+                    target_rows.len()
+                );
+                self.get_logger().warn(message.as_str());
+            }
+            vec![]
+        };
+        // Keeps the generated SQL the same from run to run
+        deletes.sort();
+
+        let message = format!(
+            "Table {} delta: {} inserts, {} updates, {} deletes",
+            table,
+            inserts.len(),
+            updates.len(),
+            deletes.len()
+        );
+        self.get_logger().info(message.as_str());
+
+        // Deletes go first so freed unique keys can be taken by the updated and new rows
+        let mut result = String::new();
+        for keys in deletes.chunks(self.config.load.batch_size.max(1)) {
+            result.push_str(
+                format!("DELETE FROM {}\nWHERE\n({});\n", table, keys.join(")\nOR (")).as_str()
+            );
+        }
+        for update in updates {
+            result.push_str(update.as_str());
+        }
+        // Rows missing from the target can't hit a duplicate key
+        let insert_query = self.generate_insert_query(
+            columns,
+            &inserts,
+            table,
+            ConflictMode::Fail
+        )?;
+        result.push_str(insert_query.as_str());
+
+        Ok(result)
+    }
+
+    // Target rows of the scope keyed by their primary key predicate,
+    // with the values laid out in the source column order
+    fn get_target_rows(
+        &self,
+        table: &str,
+        columns: &[ColumnProps],
+        key_positions: &[usize],
+        target: TableData
+    ) -> CustomResult<HashMap<String, Vec<Value>>> {
+        let positions: Vec<Option<usize>> = columns
+            .iter()
+            .map(|props| target.columns.iter().position(|target| target.name == props.name))
+            .collect();
+
+        let mut rows: HashMap<String, Vec<Value>> = HashMap::new();
+        for (row_index, row) in target.rows.into_iter().enumerate() {
+            let row: Vec<Value> = positions
+                .iter()
+                .map(|position| {
+                    position.and_then(|position| row.get(position).cloned()).unwrap_or(Value::NULL)
+                })
+                .collect();
+            let key = self.get_key_predicate(table, columns, key_positions, row_index, &row)?;
+            rows.insert(key, row);
+        }
+
+        Ok(rows)
+    }
+
+    fn get_key_predicate(
+        &self,
+        table: &str,
+        columns: &[ColumnProps],
+        key_positions: &[usize],
+        row_index: usize,
+        row: &[Value]
+    ) -> CustomResult<String> {
+        let conditions = key_positions
+            .iter()
+            .map(|position| {
+                let props = &columns[*position];
+                self.parse_mysql_value_to_string(props, &row[*position])
+                    .map(|value| format!("`{}` = {}", props.name, value))
+                    .map_err(|reason| get_decoding_error(table, props, row_index, reason))
+            })
+            .collect::<CustomResult<Vec<String>>>()?;
+
+        Ok(conditions.join(" AND "))
+    }
+
+    // Only the columns whose value changed are set
+    fn get_update_query(
+        &self,
+        table: &str,
+        columns: &[ColumnProps],
+        row_index: usize,
+        row: &[Value],
+        target_row: &[Value],
+        key: &str
+    ) -> CustomResult<String> {
+        let lenient = self.config.extract.lenient_decoding;
+        let mut assignments: Vec<String> = vec![];
+        for ((props, value), target_value) in columns.iter().zip(row).zip(target_row) {
+            if value == target_value {
+                continue;
+            }
+            let value = self
+                .parse_mysql_value_to_string(props, value)
+                .map_err(|reason| get_decoding_error(table, props, row_index, reason));
+            let value = decode_or_null(lenient, value, "NULL".to_string())?;
+            assignments.push(format!("`{}` = {}", props.name, value));
+        }
+
+        Ok(format!("UPDATE {}\nSET {}\nWHERE {};\n", table, assignments.join(", "), key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mysql::Value;
+
+    use crate::config::{ get_test_config, Config };
+    use crate::custom_error::CustomError;
+    use crate::mysql::db::ConnectionManager;
+    use crate::mysql::traits::{ ColumnProps, TableData };
+
+    use super::DeltaGenerator;
+
+    fn get_column(name: &str, key: &str) -> ColumnProps {
+        ColumnProps {
+            name: name.to_string(),
+            data_type: "varchar(255)".to_string(),
+            is_nullable: "YES".to_string(),
+            key: key.to_string(),
+            default_value: None,
+            extra: String::new(),
+        }
+    }
+
+    // The text protocol returns every value as bytes
+    fn get_row(values: &[Option<&str>]) -> Vec<Value> {
+        values
+            .iter()
+            .map(|value| {
+                value.map_or(Value::NULL, |value| Value::Bytes(value.as_bytes().to_vec()))
+            })
+            .collect()
+    }
+
+    fn get_data(rows: Vec<Vec<Value>>) -> TableData {
+        let columns = vec![get_column("id", "PRI"), get_column("name", ""), get_column("note", "")];
+
+        TableData { columns, rows }
+    }
+
+    fn get_delta(config: &Config) -> String {
+        let connections = ConnectionManager::new(config);
+        let generator = DeltaGenerator { config, connections: &connections };
+        let source = get_data(
+            vec![
+                get_row(&[Some("1"), Some("same"), None]),
+                get_row(&[Some("2"), Some("renamed"), Some("kept")]),
+                get_row(&[Some("4"), Some("new"), None])
+            ]
+        );
+        let target = get_data(
+            vec![
+                get_row(&[Some("3"), Some("gone"), None]),
+                get_row(&[Some("2"), Some("old"), Some("kept")]),
+                get_row(&[Some("1"), Some("same"), None])
+            ]
+        );
+
+        generator.get_delta_query("items", &source, &[0], target).unwrap()
+    }
+
+    fn get_delete_config() -> Config {
+        let mut config = get_test_config();
+        config.delta.enabled = true;
+        config.delta.delete_missing = true;
+        config
+    }
+
+    #[test]
+    fn rows_are_classified_by_primary_key() {
+        assert_eq!(
+            get_delta(&get_delete_config()),
+            "DELETE FROM items\nWHERE\n(`id` = '3');\n\
+             UPDATE items\nSET `name` = 'renamed'\nWHERE `id` = '2';\n\
+             INSERT INTO\nitems (`id`, `name`, `note`)\nVALUES\n('4', 'new', NULL);\n"
+        );
+    }
+
+    #[test]
+    fn update_sets_only_the_changed_columns() {
+        let query = get_delta(&get_delete_config());
+
+        assert!(query.contains("SET `name` = 'renamed'\n"));
+        assert!(!query.contains("`note` = 'kept'"));
+        assert!(!query.contains("`id` = '1'"));
+    }
+
+    #[test]
+    fn missing_rows_are_kept_by_default() {
+        let mut config = get_test_config();
+        config.delta.enabled = true;
+
+        assert!(!config.delta.delete_missing);
+        assert!(!get_delta(&config).contains("DELETE"));
+    }
+
+    #[test]
+    fn limited_or_sampled_scope_deletes_nothing() {
+        let mut config = get_delete_config();
+        config.business.limit = Some(10);
+        assert!(!config.deletes_missing_rows());
+        assert!(!get_delta(&config).contains("DELETE"));
+
+        let mut config = get_delete_config();
+        config.sample.enabled = true;
+        assert!(!config.deletes_missing_rows());
+        assert!(!get_delta(&config).contains("DELETE"));
+    }
+
+    #[test]
+    fn table_without_primary_key_is_an_error() {
+        let mut config = get_test_config();
+        // Never connected, the key check comes first
+        config.target_db = Some(config.source.clone());
+        let connections = ConnectionManager::new(&config);
+        let generator = DeltaGenerator { config: &config, connections: &connections };
+        let mut source = get_data(vec![]);
+        source.columns[0].key = String::new();
+
+        assert!(
+            matches!(
+                generator.generate("items", "SELECT * FROM items", &source, None),
+                Err(CustomError::MissingPrimaryKey { table }) if table == "items"
+            )
+        );
+    }
+}
@@ -1,8 +1,8 @@
 use mysql::PooledConn;
 
-use crate::{ config::Config, custom_error::CustomResult };
+use crate::{ config::{ Config, SampleRoot }, custom_error::CustomResult };
 
-use super::traits::TableQueryGenerator;
+use super::traits::{ TableQueryGenerator, TableScope };
 
 pub struct DoubleStagedTableQueryProvider<'config> {
     pub config: &'config Config,
@@ -18,77 +18,118 @@ impl<'config> DoubleStagedTableQueryProvider<'config> {
         connection: &mut PooledConn,
         table_prefix: &String,
         select_column: Option<String>
+    ) -> CustomResult<String> {
+        self.get_scoped_select_query(connection, table_prefix, select_column, true)
+    }
+
+    // Unlike get_select_query, expects the already resolved partition table name.
+    // The purge clears every scoped row of the target, the parent subqueries aren't limited either
+    pub fn get_delete_query(
+        &self,
+        connection: &mut PooledConn,
+        table: &String
+    ) -> CustomResult<String> {
+        let scope = self.get_scope(connection, table, false)?;
+
+        Ok(scope.get_delete_query(table))
+    }
+
+    fn get_scoped_select_query(
+        &self,
+        connection: &mut PooledConn,
+        table_prefix: &String,
+        select_column: Option<String>,
+        limited: bool
     ) -> CustomResult<String> {
         let table = self.get_table_name(table_prefix);
-        let columns = self.get_columns(connection, &table)?;
+        let scope = self.get_scope(connection, &table, limited)?;
+
+        Ok(scope.get_select_query(&table, select_column))
+    }
+
+    // The limit only goes on root tables, children keep the rows of the limited parents
+    fn get_scope(
+        &self,
+        connection: &mut PooledConn,
+        table: &String,
+        limited: bool
+    ) -> CustomResult<TableScope> {
+        let columns = self.get_columns(connection, table)?;
         let column_names: Vec<String> = columns
             .iter()
             .map(|column| column.name.clone())
             .collect();
-        let selected = self.get_select_column(select_column);
         let references = self.get_table_references(
             connection,
-            &table,
+            table,
             &self.config.source.database
         )?;
 
-        let mut query = format!("SELECT {} FROM {}", selected, table);
+        let issues_table = self.get_table_name(&String::from("issues"));
+        let mut conditions: Vec<String> = vec![];
 
         if column_names.contains(&String::from("study_id")) {
-            query.push_str(format!(" WHERE study_id = {}", self.config.business.study_id).as_str());
+            conditions.push(format!("study_id = {}", self.config.business.study_id));
         }
 
         if column_names.contains(&String::from("subject_id")) {
             if let Some(subject_id) = self.config.business.subject_id {
-                query.push_str(format!(" AND subject_id = {}", subject_id).as_str());
+                conditions.push(format!("subject_id = {}", subject_id));
+            }
+            let sample = &self.config.sample;
+            if let Some(condition) = sample.get_condition(SampleRoot::Subjects, "subject_id") {
+                conditions.push(condition);
+            }
+        }
+        if *table == issues_table {
+            if let Some(condition) = self.config.sample.get_condition(SampleRoot::Issues, "id") {
+                conditions.push(condition);
             }
         }
         if column_names.contains(&String::from("job_id")) {
             if let Some(job_id) = self.config.business.job_id {
-                query.push_str(format!(" AND job_id = {}", job_id).as_str());
+                conditions.push(format!("job_id = {}", job_id));
             }
         }
 
-        if column_names.contains(&String::from("issue_id")) {
-            let issue_table = self.get_table_name(&String::from("issues"));
-            let mut subquery = format!(
-                "SELECT id FROM {} WHERE study_id = {}",
-                issue_table,
-                self.config.business.study_id
-            );
-
-            if let Some(subject_id) = self.config.business.subject_id {
-                subquery.push_str(format!(" AND subject_id = {}", subject_id).as_str());
-            }
+        let has_issue_id =
+            column_names.contains(&String::from("issue_id")) && *table != issues_table;
+        if has_issue_id {
+            let subquery = self.get_scoped_select_query(
+                connection,
+                &String::from("issues"),
+                Some(String::from("id")),
+                limited
+            )?;
 
-            query.push_str(format!(" AND issue_id IN ({})", subquery).as_str());
+            conditions.push(format!("issue_id IN (\n{}\n)", subquery));
         }
 
         for reference in references.iter() {
-            let subquery = self.get_select_query(
+            let subquery = self.get_scoped_select_query(
                 connection,
                 &reference.referenced_table_name,
-                Some(reference.referenced_column_name.clone())
+                Some(reference.referenced_column_name.clone()),
+                limited
             )?;
 
-            query.push_str(
-                format!(" AND {} IN (\n{}\n)", reference.column_name, subquery).as_str()
-            );
+            conditions.push(format!("{} IN (\n{}\n)", reference.column_name, subquery));
         }
 
-        if let Some(limit) = self.config.business.limit {
-            query.push_str(format!(" LIMIT {}", limit).as_str());
-        }
+        let filter = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!(" WHERE {}", conditions.join(" AND "))
+        };
+        let is_root = !has_issue_id && references.is_empty();
+        let limit = if is_root && limited { self.get_limit_clause(table, &columns) } else { None };
 
-        Ok(query)
+        Ok(TableScope { filter, limit })
     }
+}
 
-    fn get_select_column(&self, select_column: Option<String>) -> String {
-        match select_column {
-            Some(column) => column,
-            None => String::from("*"),
-        }
+impl<'config> TableQueryGenerator for DoubleStagedTableQueryProvider<'config> {
+    fn get_config(&self) -> &Config {
+        self.config
     }
 }
-
-impl<'config> TableQueryGenerator for DoubleStagedTableQueryProvider<'config> {}
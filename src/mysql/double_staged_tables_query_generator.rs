@@ -1,42 +1,151 @@
+use std::time::Instant;
+
+use mysql::PooledConn;
+
 use crate::{
-    config::Config,
+    cancellation::check_cancelled,
+    checkpoint::CheckpointStore,
+    config::{ Config, ConflictMode },
     custom_error::CustomResult,
     logger::LoggerTrait,
-    traits::TablesInsertQueryGeneratorTrait,
+    retry::with_retry,
+    summary::{ RunSummary, TableStatus },
+    traits::{ DOUBLE_STAGED_TABLES, TableQuery, TablesInsertQueryGeneratorTrait },
+    workers::run_ordered,
 };
 
 use super::{
-    db::get_connection,
+    db::ConnectionManager,
+    delta::DeltaGenerator,
     double_staged_table_query_provider::DoubleStagedTableQueryProvider,
-    traits::TableQueryGenerator,
+    traits::{ StatementTimeoutGuard, TableQueryGenerator },
 };
 
 pub struct DoubleStagedTablesQueryGenerator<'config> {
     pub config: &'config Config,
+    pub checkpoint: &'config CheckpointStore,
+    pub summary: &'config RunSummary,
+    pub connections: &'config ConnectionManager,
 }
 
 impl<'config> LoggerTrait for DoubleStagedTablesQueryGenerator<'config> {}
 impl<'config> TablesInsertQueryGeneratorTrait for DoubleStagedTablesQueryGenerator<'config> {
-    fn generate(&self) -> CustomResult<Option<String>> {
+    fn generate(&self) -> CustomResult<Vec<TableQuery>> {
+        let provider = DoubleStagedTableQueryProvider { config: self.config };
+        let results = run_ordered(
+            self.config.extract.workers,
+            &self.config.tables.double_partitioned_tables,
+            |table_prefix| self.generate_table(&provider, table_prefix)
+        );
+
+        results.into_iter().filter_map(Result::transpose).collect()
+    }
+
+    fn generate_purge(&self) -> CustomResult<Vec<TableQuery>> {
         let logger = self.get_logger();
-        let mut result = String::new();
-        let mut connection = get_connection(&self.config.source)?;
-        let provider = DoubleStagedTableQueryProvider { config: &self.config };
-        for table_prefix in &self.config.tables.double_partitioned_tables {
-            let table = provider.get_table_name(table_prefix);
-            let mut select_query = provider.get_select_query(&mut connection, table_prefix, None)?;
-            select_query.push_str(";");
-            logger.info(format!("\nselect query:\n\n {}\n\n", select_query).as_str());
-            let data = provider.get_data(&mut connection, &table, &select_query)?;
-            let insert_query = provider.generate_insert_query(&data, &table)?;
-            logger.info(format!("\ninsert query:\n\n {}\n\n", insert_query).as_str());
-            result.push_str(insert_query.as_str());
+        let mut result: Vec<TableQuery> = vec![];
+        let mut connection = self.connections.get_connection(&self.config.source)?;
+        let provider = DoubleStagedTableQueryProvider { config: self.config };
+        let tables: Vec<String> = self.config.tables.double_partitioned_tables
+            .iter()
+            .map(|table_prefix| provider.get_table_name(table_prefix))
+            .collect();
+        let tables = provider.sort_by_dependencies(
+            &mut connection,
+            &tables,
+            &self.config.source.database
+        )?;
+        for table in tables.iter().rev() {
+            let delete_query = provider.get_delete_query(&mut connection, table)?;
+            logger.info(format!("\ndelete query:\n\n {}\n\n", delete_query).as_str());
+            result.push(TableQuery {
+                table: table.clone(),
+                query: format!("{};\n", delete_query),
+                rows: 0,
+                conflict_mode: ConflictMode::default(),
+                data: None,
+            });
         }
 
-        if result.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(result))
+        Ok(result)
+    }
+}
+
+impl<'config> DoubleStagedTablesQueryGenerator<'config> {
+    fn generate_table(
+        &self,
+        provider: &DoubleStagedTableQueryProvider,
+        table_prefix: &String
+    ) -> CustomResult<Option<TableQuery>> {
+        let table = provider.get_table_name(table_prefix);
+        let conflict_mode = self.config.insert.get_partitioned_conflict_mode(&table, table_prefix);
+        let extracted = self.checkpoint.get_extracted(DOUBLE_STAGED_TABLES, &table, conflict_mode);
+        if let Some(table_query) = extracted {
+            self.summary.record(DOUBLE_STAGED_TABLES, &table_query.table, TableStatus::Extracted);
+            let rows = table_query.rows;
+            self.summary.record_checkpoint_rows(DOUBLE_STAGED_TABLES, &table_query.table, rows);
+            return Ok(Some(table_query));
         }
+
+        let operation = format!("Extracting table {}", table);
+        let started = Instant::now();
+        let result = check_cancelled().and_then(|_| {
+            with_retry(&self.config.retry, &operation, |_| {
+                let mut connection = self.connections.acquire_connection(&self.config.source)?;
+                self.extract_table(&mut connection, provider, table_prefix, conflict_mode)
+            })
+        });
+        self.summary.record_extract_time(DOUBLE_STAGED_TABLES, &table, started.elapsed());
+        let result = self.summary.settle_extract(
+            self.config,
+            DOUBLE_STAGED_TABLES,
+            &table,
+            result
+        )?;
+        let Some(table_query) = result else {
+            return Ok(None);
+        };
+        self.summary.record_rows(DOUBLE_STAGED_TABLES, &table, table_query.rows);
+        self.checkpoint.mark_extracted(DOUBLE_STAGED_TABLES, &table_query)?;
+
+        Ok(Some(table_query))
+    }
+
+    fn extract_table(
+        &self,
+        connection: &mut PooledConn,
+        provider: &DoubleStagedTableQueryProvider,
+        table_prefix: &String,
+        conflict_mode: ConflictMode
+    ) -> CustomResult<TableQuery> {
+        let logger = self.get_logger();
+        let table = provider.get_table_name(table_prefix);
+        let timeout_ms = self.config.timeout.get_partitioned_statement_timeout(
+            &table,
+            table_prefix
+        );
+        let mut connection = StatementTimeoutGuard::new(connection, timeout_ms)?;
+        let mut select_query = provider.get_select_query(&mut connection, table_prefix, None)?;
+        select_query.push(';');
+        logger.info(format!("\nselect query:\n\n {}\n\n", select_query).as_str());
+        self.summary.record_query(DOUBLE_STAGED_TABLES, &table, &select_query);
+        let data = provider.get_data(&mut connection, &table, &select_query)?;
+        let insert_query = if self.config.delta.enabled {
+            let delta = DeltaGenerator { config: self.config, connections: self.connections };
+            delta.generate(&table, &select_query, &data, timeout_ms)?
+        } else if self.config.renders_insert_sql() {
+            provider.generate_insert_query(&data.columns, &data.rows, &table, conflict_mode)?
+        } else {
+            String::new()
+        };
+        logger.info(format!("\ninsert query:\n\n {}\n\n", insert_query).as_str());
+
+        Ok(TableQuery {
+            table,
+            query: insert_query,
+            rows: data.rows.len(),
+            conflict_mode,
+            data: self.config.keeps_table_data().then_some(data),
+        })
     }
 }
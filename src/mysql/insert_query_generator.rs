@@ -1,14 +1,19 @@
 use crate::{
+    checkpoint::CheckpointStore,
     custom_error::CustomResult,
     logger::LoggerTrait,
+    summary::RunSummary,
     mysql::double_staged_tables_query_generator::DoubleStagedTablesQueryGenerator,
     traits::{ InsertQueries, TablesInsertQueryGeneratorTrait, TechnologyInsertGeneratorTrait },
 };
 
-use super::batch_tables_query_generator::BatchTablesQueryGenerator;
+use super::{ batch_tables_query_generator::BatchTablesQueryGenerator, db::ConnectionManager };
 
 pub struct InsertQueryGenerator<'config> {
     pub config: &'config crate::config::Config,
+    pub checkpoint: &'config CheckpointStore,
+    pub summary: &'config RunSummary,
+    pub connections: &'config ConnectionManager,
 }
 
 impl<'config> LoggerTrait for InsertQueryGenerator<'config> {}
@@ -17,20 +22,38 @@ impl<'config> TechnologyInsertGeneratorTrait for InsertQueryGenerator<'config> {
         let logger = self.get_logger();
         logger.info("Generating insert statement for mysql");
 
-        let batch_tables_generator = BatchTablesQueryGenerator { config: &self.config };
+        let batch_tables_generator = BatchTablesQueryGenerator {
+            config: self.config,
+            checkpoint: self.checkpoint,
+            summary: self.summary,
+            connections: self.connections,
+        };
         let batch_tables_sql = batch_tables_generator.generate()?;
 
         let double_staged_tables_generator = DoubleStagedTablesQueryGenerator {
-            config: &self.config,
+            config: self.config,
+            checkpoint: self.checkpoint,
+            summary: self.summary,
+            connections: self.connections,
         };
         let double_staged_tables_sql = double_staged_tables_generator.generate()?;
 
+        let mut purge_sql = vec![];
+        if self.config.insert.purge_before_load && self.config.delta.enabled {
+            logger.warn("Delta sync deletes only what it has to, purge_before_load is ignored");
+        } else if self.config.insert.purge_before_load {
+            // Partitioned tables hang off the batch tables, so they are purged first
+            purge_sql.extend(double_staged_tables_generator.generate_purge()?);
+            purge_sql.extend(batch_tables_generator.generate_purge()?);
+        }
+
         logger.info("Generated insert statement for mysql");
         let result = InsertQueries {
+            purge_tables: purge_sql,
             batch_tables: batch_tables_sql,
             double_staged_tables: double_staged_tables_sql,
-            triple_staged_tables: None,
-            redshift_tables: None,
+            triple_staged_tables: vec![],
+            redshift_tables: vec![],
         };
         Ok(result)
     }
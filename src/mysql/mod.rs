@@ -1,8 +1,15 @@
 pub mod insert_query_generator;
 pub mod data_saver;
-mod db;
+pub mod db;
 mod batch_tables_query_generator;
 mod batch_table_query_provider;
-mod traits;
+pub mod traits;
+mod bulk_loader;
+mod delta;
 mod double_staged_tables_query_generator;
 mod double_staged_table_query_provider;
+pub mod verifier;
+pub mod planner;
+pub mod reference_checker;
+pub mod schema_checker;
+pub mod sampler;
@@ -0,0 +1,159 @@
+use mysql::{ prelude::Queryable, PooledConn, Row };
+
+use crate::{
+    cancellation::check_cancelled,
+    config::{ Config, ConflictMode },
+    custom_error::{ CustomError, CustomResult },
+    logger::LoggerTrait,
+    plan::{ get_target_actions, TablePlan },
+    retry::with_retry,
+    traits::{ BATCH_TABLES, DOUBLE_STAGED_TABLES },
+};
+
+use super::{
+    batch_table_query_provider::BatchTableQueryProvider,
+    db::ConnectionManager,
+    double_staged_table_query_provider::DoubleStagedTableQueryProvider,
+    traits::{ StatementTimeoutGuard, TableQueryGenerator },
+};
+
+// Builds every select query the run would execute and explains it, no rows are fetched
+pub struct Planner<'config> {
+    pub config: &'config Config,
+    pub connections: &'config ConnectionManager,
+    // Also runs COUNT(*) over each scoped select
+    pub count_rows: bool,
+}
+
+impl<'config> LoggerTrait for Planner<'config> {}
+impl<'config> TableQueryGenerator for Planner<'config> {
+    fn get_config(&self) -> &Config {
+        self.config
+    }
+}
+
+impl Planner<'_> {
+    pub fn plan(&self) -> CustomResult<Vec<TablePlan>> {
+        let mut plans: Vec<TablePlan> = vec![];
+
+        let batch_provider = BatchTableQueryProvider { config: self.config };
+        for table in &self.config.tables.batch_tables {
+            let timeout_ms = self.config.timeout.get_statement_timeout(table);
+            let conflict_mode = self.config.insert.get_conflict_mode(table);
+            plans.push(
+                self.plan_table(BATCH_TABLES, table, timeout_ms, conflict_mode, |connection| {
+                    batch_provider.get_select_query(connection, table, None)
+                })?
+            );
+        }
+
+        let double_staged_provider = DoubleStagedTableQueryProvider { config: self.config };
+        for table_prefix in &self.config.tables.double_partitioned_tables {
+            let table = double_staged_provider.get_table_name(table_prefix);
+            let timeout_ms = self.config.timeout.get_partitioned_statement_timeout(
+                &table,
+                table_prefix
+            );
+            let conflict_mode = self.config.insert.get_partitioned_conflict_mode(
+                &table,
+                table_prefix
+            );
+            plans.push(
+                self.plan_table(
+                    DOUBLE_STAGED_TABLES,
+                    &table,
+                    timeout_ms,
+                    conflict_mode,
+                    |connection| {
+                        double_staged_provider.get_select_query(connection, table_prefix, None)
+                    }
+                )?
+            );
+        }
+
+        Ok(plans)
+    }
+
+    fn plan_table<F>(
+        &self,
+        category: &str,
+        table: &str,
+        timeout_ms: Option<u64>,
+        conflict_mode: ConflictMode,
+        get_select_query: F
+    ) -> CustomResult<TablePlan>
+        where F: Fn(&mut PooledConn) -> CustomResult<String>
+    {
+        check_cancelled()?;
+        let operation = format!("Planning table {}", table);
+
+        with_retry(&self.config.retry, &operation, |_| {
+            let mut connection = self.connections.acquire_connection(&self.config.source)?;
+            let mut connection = StatementTimeoutGuard::new(&mut connection, timeout_ms)?;
+            let query = get_select_query(&mut connection)?;
+            let estimated_rows = self.explain(&mut connection, table, &query)?;
+            let row_length = self.get_average_row_length(&mut connection, table)?;
+            let counted_rows = if self.count_rows {
+                Some(self.count_rows(&mut connection, table, &query)?)
+            } else {
+                None
+            };
+
+            Ok(TablePlan {
+                category: category.to_string(),
+                table: table.to_string(),
+                query: query.clone(),
+                estimated_rows,
+                estimated_bytes: estimated_rows.zip(row_length).map(|(rows, length)| rows * length),
+                counted_rows,
+                actions: get_target_actions(self.config, category, conflict_mode),
+            })
+        })
+    }
+
+    // Rows the optimizer expects from the planned table, scaled by its filtered percentage
+    fn explain(
+        &self,
+        connection: &mut PooledConn,
+        table: &str,
+        select_query: &str
+    ) -> CustomResult<Option<u64>> {
+        let query = format!("EXPLAIN {}", select_query);
+        let rows: Vec<Row> = connection
+            .query(&query)
+            .map_err(|err| CustomError::from(err).with_table(table).with_sql(&query))?;
+
+        let row = rows
+            .iter()
+            .find(|row| get_column::<String>(row, "table").as_deref() == Some(table))
+            .or(rows.first());
+
+        Ok(
+            row.and_then(|row| {
+                let estimated = get_column::<u64>(row, "rows")?;
+                let filtered = get_column::<f64>(row, "filtered").unwrap_or(100.0);
+                Some(((estimated as f64) * filtered / 100.0).round() as u64)
+            })
+        )
+    }
+
+    fn get_average_row_length(
+        &self,
+        connection: &mut PooledConn,
+        table: &str
+    ) -> CustomResult<Option<u64>> {
+        let query =
+            "SELECT AVG_ROW_LENGTH FROM information_schema.TABLES
+            WHERE TABLE_SCHEMA = ? AND TABLE_NAME = ?";
+
+        connection
+            .exec_first::<Option<u64>, _, _>(query, (&self.config.source.database, table))
+            .map(Option::flatten)
+            .map_err(|err| CustomError::from(err).with_table(table))
+    }
+}
+
+// None for NULL, a missing column or a value of another type
+fn get_column<T: mysql::prelude::FromValue>(row: &Row, column: &str) -> Option<T> {
+    row.get_opt::<Option<T>, _>(column).and_then(Result::ok).flatten()
+}
@@ -0,0 +1,280 @@
+use std::collections::{ BTreeSet, HashMap };
+
+use mysql::{ prelude::Queryable, PooledConn, Value };
+
+use crate::{
+    cancellation::check_cancelled,
+    config::Config,
+    custom_error::{ CustomError, CustomResult },
+    logger::LoggerTrait,
+    retry::with_retry,
+    summary::{ ReferenceCheck, RunSummary },
+    traits::{ InsertQueries, BATCH_TABLES, DOUBLE_STAGED_TABLES },
+};
+
+use super::{
+    db::ConnectionManager,
+    double_staged_table_query_provider::DoubleStagedTableQueryProvider,
+    traits::{ ColumnProps, TableData, TableQueryGenerator },
+};
+
+// Orphan values kept for the run summary and the report
+const MAX_REPORTED_ORPHANS: usize = 20;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Reference {
+    category: &'static str,
+    table: String,
+    column: String,
+    parent_table: String,
+    parent_column: String,
+}
+
+// Confirms every foreign key value of the extracted rows points at a parent row
+// that is either in the extract or already in the target
+pub struct ReferenceChecker<'config> {
+    pub config: &'config Config,
+    pub summary: &'config RunSummary,
+    pub connections: &'config ConnectionManager,
+}
+
+impl<'config> LoggerTrait for ReferenceChecker<'config> {}
+impl<'config> TableQueryGenerator for ReferenceChecker<'config> {
+    fn get_config(&self) -> &Config {
+        self.config
+    }
+}
+
+impl ReferenceChecker<'_> {
+    pub fn check(&self, data: &InsertQueries) -> CustomResult<()> {
+        let logger = self.get_logger();
+        logger.info("Checking references of the extracted rows");
+
+        let mut extracted: HashMap<&str, (&'static str, &TableData)> = HashMap::new();
+        let categories = [
+            (BATCH_TABLES, &data.batch_tables),
+            (DOUBLE_STAGED_TABLES, &data.double_staged_tables),
+        ];
+        for (category, queries) in categories {
+            for table_query in queries {
+                match &table_query.data {
+                    Some(table_data) => {
+                        extracted.insert(&table_query.table, (category, table_data));
+                    }
+                    None => {
+                        let message = format!(
+                            "Table {} was reused from the checkpoint, references not checked",
+                            table_query.table
+                        );
+                        logger.warn(message.as_str());
+                    }
+                }
+            }
+        }
+
+        let references = with_retry(&self.config.retry, "Reading references", |_| {
+            let mut connection = self.connections.acquire_connection(&self.config.source)?;
+            self.get_references(&mut connection, &extracted)
+        })?;
+
+        let mut failures: Vec<CustomError> = vec![];
+        for reference in references {
+            check_cancelled()?;
+            let Some((_, child)) = extracted.get(reference.table.as_str()) else {
+                continue;
+            };
+            let parent = extracted.get(reference.parent_table.as_str()).map(|(_, data)| *data);
+            let Some(orphans) = self.get_orphans(&reference, child, parent)? else {
+                continue;
+            };
+
+            let check = ReferenceCheck {
+                category: reference.category.to_string(),
+                table: reference.table.clone(),
+                column: reference.column.clone(),
+                parent_table: reference.parent_table.clone(),
+                parent_column: reference.parent_column.clone(),
+                orphans: orphans.len(),
+                orphan_values: orphans.iter().take(MAX_REPORTED_ORPHANS).cloned().collect(),
+            };
+            self.summary.record_reference(check);
+            if orphans.is_empty() {
+                continue;
+            }
+
+            let err = CustomError::OrphanRows {
+                table: reference.table.clone(),
+                column: reference.column.clone(),
+                parent_table: reference.parent_table.clone(),
+                orphans: orphans.len(),
+            };
+            logger.warn(err.to_string().as_str());
+            if self.config.references.fail_on_orphans {
+                self.summary.record_failure(reference.category, &reference.table, &err);
+                failures.push(err);
+            } else {
+                self.summary.record_warning(reference.category, &reference.table, &err.to_string());
+            }
+        }
+        logger.info("References checked");
+
+        if failures.is_empty() || self.config.errors.continue_on_error {
+            Ok(())
+        } else {
+            Err(CustomError::TablesFailed(failures))
+        }
+    }
+
+    // Declared foreign keys, configured rules and the issue_id rule of the double staged scope
+    fn get_references(
+        &self,
+        connection: &mut PooledConn,
+        extracted: &HashMap<&str, (&'static str, &TableData)>
+    ) -> CustomResult<BTreeSet<Reference>> {
+        let provider = DoubleStagedTableQueryProvider { config: self.config };
+        let issues_table = provider.get_table_name(&String::from("issues"));
+        let mut references: BTreeSet<Reference> = BTreeSet::new();
+
+        for (table, (category, table_data)) in extracted {
+            let table = table.to_string();
+            let declared = self.get_table_references(
+                connection,
+                &table,
+                &self.config.source.database
+            )?;
+            references.extend(
+                declared.into_iter().map(|usage| Reference {
+                    category,
+                    table: table.clone(),
+                    column: usage.column_name,
+                    parent_table: usage.referenced_table_name,
+                    parent_column: usage.referenced_column_name,
+                })
+            );
+
+            let has_issue_id = table_data.columns.iter().any(|props| props.name == "issue_id");
+            if *category == DOUBLE_STAGED_TABLES && has_issue_id && table != issues_table {
+                references.insert(Reference {
+                    category,
+                    table: table.clone(),
+                    column: "issue_id".to_string(),
+                    parent_table: issues_table.clone(),
+                    parent_column: "id".to_string(),
+                });
+            }
+        }
+
+        for rule in &self.config.references.rules {
+            let table = self.resolve_table_name(&provider, &rule.table);
+            let Some((category, _)) = extracted.get(table.as_str()) else {
+                continue;
+            };
+            references.insert(Reference {
+                category,
+                table,
+                column: rule.column.clone(),
+                parent_table: self.resolve_table_name(&provider, &rule.parent_table),
+                parent_column: rule.parent_column.clone(),
+            });
+        }
+
+        Ok(references)
+    }
+
+    fn resolve_table_name(
+        &self,
+        provider: &DoubleStagedTableQueryProvider,
+        table: &String
+    ) -> String {
+        if self.config.tables.double_partitioned_tables.contains(table) {
+            provider.get_table_name(table)
+        } else {
+            table.clone()
+        }
+    }
+
+    // None when the child column isn't part of the extract
+    fn get_orphans(
+        &self,
+        reference: &Reference,
+        child: &TableData,
+        parent: Option<&TableData>
+    ) -> CustomResult<Option<BTreeSet<String>>> {
+        let Some(props) = child.columns.iter().find(|props| props.name == reference.column) else {
+            let message = format!(
+                "Column {}.{} doesn't exist, reference skipped",
+                reference.table,
+                reference.column
+            );
+            self.get_logger().warn(message.as_str());
+            return Ok(None);
+        };
+        let mut orphans = self.get_values(child, &reference.column, props);
+
+        if let Some(parent) = parent {
+            let parent_values = self.get_values(parent, &reference.parent_column, props);
+            orphans.retain(|value| !parent_values.contains(value));
+        }
+
+        if let (false, Some(target_db)) = (orphans.is_empty(), &self.config.target_db) {
+            let operation = format!("Looking up parents of {} in target", reference.table);
+            let found = with_retry(&self.config.retry, &operation, |_| {
+                let mut connection = self.connections.acquire_connection(target_db)?;
+                self.get_target_values(&mut connection, reference, props, &orphans)
+            })?;
+            orphans.retain(|value| !found.contains(value));
+        }
+
+        Ok(Some(orphans))
+    }
+
+    // Distinct non NULL values of a column rendered as SQL literals, both sides of a reference
+    // are rendered with the child column so they compare equal
+    fn get_values(&self, data: &TableData, column: &str, props: &ColumnProps) -> BTreeSet<String> {
+        let Some(position) = data.columns.iter().position(|props| props.name == column) else {
+            return BTreeSet::new();
+        };
+
+        data.rows
+            .iter()
+            .filter_map(|row| row.get(position))
+            .filter(|value| **value != Value::NULL)
+            .filter_map(|value| self.parse_mysql_value_to_string(props, value).ok())
+            .collect()
+    }
+
+    fn get_target_values(
+        &self,
+        connection: &mut PooledConn,
+        reference: &Reference,
+        props: &ColumnProps,
+        values: &BTreeSet<String>
+    ) -> CustomResult<BTreeSet<String>> {
+        let values: Vec<&String> = values.iter().collect();
+        let mut found: BTreeSet<String> = BTreeSet::new();
+
+        for chunk in values.chunks(self.config.load.batch_size.max(1)) {
+            let chunk: Vec<&str> = chunk
+                .iter()
+                .map(|value| value.as_str())
+                .collect();
+            let query = format!(
+                "SELECT DISTINCT `{}` FROM {} WHERE `{}` IN ({})",
+                reference.parent_column,
+                reference.parent_table,
+                reference.parent_column,
+                chunk.join(", ")
+            );
+            let rows: Vec<Value> = connection
+                .query(&query)
+                .map_err(|err| {
+                    CustomError::from(err).with_table(&reference.parent_table).with_sql(&query)
+                })?;
+            found.extend(
+                rows.iter().filter_map(|value| self.parse_mysql_value_to_string(props, value).ok())
+            );
+        }
+
+        Ok(found)
+    }
+}
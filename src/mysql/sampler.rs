@@ -0,0 +1,244 @@
+use mysql::{ prelude::Queryable, PooledConn, Value };
+
+use crate::{
+    config::{ Config, SampleRoot },
+    custom_error::{ CustomError, CustomResult },
+    logger::LoggerTrait,
+    retry::with_retry,
+};
+
+use super::{
+    batch_table_query_provider::BatchTableQueryProvider,
+    db::ConnectionManager,
+    double_staged_table_query_provider::DoubleStagedTableQueryProvider,
+};
+
+// Picks the sampled roots from the source once, every later query filters by the same keys
+pub struct Sampler<'config> {
+    pub config: &'config Config,
+    pub connections: &'config ConnectionManager,
+}
+
+impl<'config> LoggerTrait for Sampler<'config> {}
+impl Sampler<'_> {
+    // Root keys as SQL literals, ordered by key
+    pub fn sample(&self) -> CustomResult<Vec<String>> {
+        let logger = self.get_logger();
+        let sample = &self.config.sample;
+        if self.config.business.limit.is_some() {
+            logger.warn("Sampling picks the rows, limit is ignored");
+        }
+
+        let roots = self.read_keys("Sampling roots", |connection| {
+            let (key, population) = self.get_population_query(connection)?;
+            Ok(self.get_sample_query(&key, &population))
+        })?;
+
+        let message = format!(
+            "Picked {} {:?} roots out of {} requested with seed {}",
+            roots.len(),
+            sample.root,
+            sample.size,
+            sample.seed
+        );
+        logger.info(message.as_str());
+
+        Ok(roots)
+    }
+
+    // Subjects of the limited subjects table as SQL literals, ordered by key
+    pub fn get_limited_subjects(&self) -> CustomResult<Vec<String>> {
+        let subjects = self.read_keys("Reading limited subjects", |connection| {
+            self.get_limited_subjects_query(connection)
+        })?;
+        let message = format!("Limit keeps {} subjects", subjects.len());
+        self.get_logger().info(message.as_str());
+
+        Ok(subjects)
+    }
+
+    fn read_keys(
+        &self,
+        operation: &str,
+        get_query: impl Fn(&mut PooledConn) -> CustomResult<String>
+    ) -> CustomResult<Vec<String>> {
+        with_retry(&self.config.retry, operation, |_| {
+            let mut connection = self.connections.acquire_connection(&self.config.source)?;
+            let query = get_query(&mut connection)?;
+            connection
+                .query::<Value, _>(&query)
+                .map(|keys| {
+                    keys.iter()
+                        .map(|key| key.as_sql(false))
+                        .collect::<Vec<String>>()
+                })
+                .map_err(|err| CustomError::from(err).with_sql(&query))
+        })
+    }
+
+    // The select of the subjects table takes the limit, so these are the subjects whose rows
+    // the source copy keeps
+    fn get_limited_subjects_query(&self, connection: &mut PooledConn) -> CustomResult<String> {
+        let provider = DoubleStagedTableQueryProvider { config: self.config };
+        let table = &self.config.sample.subjects_table;
+        let subjects = provider.get_select_query(
+            connection,
+            table,
+            Some(String::from("subject_id"))
+        )?;
+
+        Ok(
+            format!(
+                "SELECT subject_id FROM (\n{}\n) AS subjects\n\
+                WHERE subject_id IS NOT NULL GROUP BY subject_id\nORDER BY subject_id",
+                subjects
+            )
+        )
+    }
+
+    // Ordering by a seeded hash of the key keeps the pick stable while rows are added elsewhere
+    fn get_sample_query(&self, key: &str, population: &str) -> String {
+        let sample = &self.config.sample;
+        let order = format!("MD5(CONCAT('{}-', {}))", sample.seed, key);
+
+        let query = match &sample.stratify_by {
+            None =>
+                format!(
+                    "SELECT {} FROM (\nSELECT {} FROM (\n{}\n) AS population\n\
+                    WHERE {} IS NOT NULL GROUP BY {}\nORDER BY {}\nLIMIT {}\n) AS sampled\n\
+                    ORDER BY {}",
+                    key,
+                    key,
+                    population,
+                    key,
+                    key,
+                    order,
+                    sample.size,
+                    key
+                ),
+            // Every stratum gets its share of the size rounded up, so a small stratum keeps
+            // at least one root and the sample can exceed the size by the number of strata
+            Some(_) =>
+                format!(
+                    "SELECT {} FROM (\nSELECT {}, \
+                    ROW_NUMBER() OVER (PARTITION BY stratum ORDER BY {}) AS sample_rank, \
+                    COUNT(*) OVER (PARTITION BY stratum) AS stratum_size, \
+                    COUNT(*) OVER () AS population_size\n\
+                    FROM (\nSELECT {}, MIN(stratum) AS stratum FROM (\n{}\n) AS population\n\
+                    WHERE {} IS NOT NULL GROUP BY {}\n) AS roots\n) AS ranked\n\
+                    WHERE sample_rank <= CEIL({} * stratum_size / population_size)\n\
+                    ORDER BY {}",
+                    key,
+                    key,
+                    order,
+                    key,
+                    population,
+                    key,
+                    key,
+                    sample.size,
+                    key
+                ),
+        };
+
+        query
+    }
+
+    // Root key column and the scoped select of the root table
+    fn get_population_query(&self, connection: &mut PooledConn) -> CustomResult<(String, String)> {
+        let sample = &self.config.sample;
+        let key = match sample.root {
+            SampleRoot::Subjects => "subject_id",
+            SampleRoot::Issues | SampleRoot::BatchRuns => "id",
+        };
+        let selected = match &sample.stratify_by {
+            Some(column) => format!("{}, `{}` AS stratum", key, column),
+            None => key.to_string(),
+        };
+
+        let population = match sample.root {
+            SampleRoot::Subjects => {
+                let provider = DoubleStagedTableQueryProvider { config: self.config };
+                provider.get_select_query(connection, &sample.subjects_table, Some(selected))?
+            }
+            SampleRoot::Issues => {
+                let provider = DoubleStagedTableQueryProvider { config: self.config };
+                provider.get_select_query(connection, &String::from("issues"), Some(selected))?
+            }
+            SampleRoot::BatchRuns => {
+                let provider = BatchTableQueryProvider { config: self.config };
+                let table = String::from("cb_batch_runs");
+                provider.get_select_query(connection, &table, Some(selected))?
+            }
+        };
+
+        Ok((key.to_string(), population))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{ get_test_config, Config };
+    use crate::mysql::db::ConnectionManager;
+
+    use super::Sampler;
+
+    fn get_sample_config(stratify_by: Option<&str>) -> Config {
+        let mut config = get_test_config();
+        config.sample.enabled = true;
+        config.sample.size = 5;
+        config.sample.seed = 42;
+        config.sample.stratify_by = stratify_by.map(str::to_string);
+        config
+    }
+
+    fn get_sample_query(config: &Config, population: &str) -> String {
+        let connections = ConnectionManager::new(config);
+        let sampler = Sampler { config, connections: &connections };
+
+        sampler.get_sample_query("subject_id", population)
+    }
+
+    #[test]
+    fn roots_are_picked_by_a_seeded_hash_and_returned_in_key_order() {
+        let config = get_sample_config(None);
+
+        assert_eq!(
+            get_sample_query(&config, "SELECT subject_id FROM subjects"),
+            "SELECT subject_id FROM (\nSELECT subject_id FROM (\nSELECT subject_id FROM subjects\n\
+             ) AS population\nWHERE subject_id IS NOT NULL GROUP BY subject_id\n\
+             ORDER BY MD5(CONCAT('42-', subject_id))\nLIMIT 5\n) AS sampled\nORDER BY subject_id"
+        );
+    }
+
+    #[test]
+    fn strata_are_ranked_by_the_seeded_hash_and_get_their_share() {
+        let config = get_sample_config(Some("site"));
+
+        assert_eq!(
+            get_sample_query(&config, "SELECT subject_id, `site` AS stratum FROM subjects"),
+            "SELECT subject_id FROM (\nSELECT subject_id, ROW_NUMBER() OVER (PARTITION BY stratum \
+             ORDER BY MD5(CONCAT('42-', subject_id))) AS sample_rank, \
+             COUNT(*) OVER (PARTITION BY stratum) AS stratum_size, \
+             COUNT(*) OVER () AS population_size\nFROM (\n\
+             SELECT subject_id, MIN(stratum) AS stratum FROM (\n\
+             SELECT subject_id, `site` AS stratum FROM subjects\n) AS population\n\
+             WHERE subject_id IS NOT NULL GROUP BY subject_id\n) AS roots\n) AS ranked\n\
+             WHERE sample_rank <= CEIL(5 * stratum_size / population_size)\nORDER BY subject_id"
+        );
+    }
+
+    #[test]
+    fn seed_changes_the_pick_order() {
+        let config = get_sample_config(None);
+        let mut reseeded = get_sample_config(None);
+        reseeded.sample.seed = 7;
+
+        let query = get_sample_query(&config, "SELECT subject_id FROM subjects");
+        assert_eq!(query, get_sample_query(&config, "SELECT subject_id FROM subjects"));
+        assert!(
+            get_sample_query(&reseeded, "SELECT subject_id FROM subjects").contains(
+                "ORDER BY MD5(CONCAT('7-', subject_id))"
+            )
+        );
+    }
+}
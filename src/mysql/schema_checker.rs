@@ -0,0 +1,467 @@
+use mysql::{ prelude::Queryable, PooledConn };
+
+use crate::{
+    cancellation::check_cancelled,
+    config::{ Config, DbConfig, SchemaPolicy },
+    custom_error::{ CustomError, CustomResult },
+    logger::LoggerTrait,
+    retry::with_retry,
+    summary::{ RunSummary, SchemaDifference },
+    traits::{ BATCH_TABLES, DOUBLE_STAGED_TABLES },
+};
+
+use super::{
+    db::ConnectionManager,
+    double_staged_table_query_provider::DoubleStagedTableQueryProvider,
+    traits::{ ColumnProps, TableQueryGenerator },
+};
+
+// Compares the columns of every MySQL table on source and target before anything is extracted
+pub struct SchemaChecker<'config> {
+    pub config: &'config Config,
+    pub summary: &'config RunSummary,
+    pub connections: &'config ConnectionManager,
+}
+
+impl<'config> LoggerTrait for SchemaChecker<'config> {}
+impl<'config> TableQueryGenerator for SchemaChecker<'config> {
+    fn get_config(&self) -> &Config {
+        self.config
+    }
+}
+
+impl SchemaChecker<'_> {
+    pub fn check(&self) -> CustomResult<()> {
+        let logger = self.get_logger();
+        let Some(target_db) = &self.config.target_db else {
+            logger.warn("No target DB configured, the schema is not checked");
+            return Ok(());
+        };
+        logger.info("Checking the target schema");
+
+        let mut tables: Vec<(&'static str, String)> = self.config.tables.batch_tables
+            .iter()
+            .map(|table| (BATCH_TABLES, table.clone()))
+            .collect();
+        let provider = DoubleStagedTableQueryProvider { config: self.config };
+        tables.extend(
+            self.config.tables.double_partitioned_tables
+                .iter()
+                .map(|prefix| (DOUBLE_STAGED_TABLES, provider.get_table_name(prefix)))
+        );
+
+        let mut failures: Vec<CustomError> = vec![];
+        for (category, table) in tables {
+            check_cancelled()?;
+            let operation = format!("Comparing schema of table {}", table);
+            let differences = with_retry(&self.config.retry, &operation, |_| {
+                self.compare_table(category, &table, target_db)
+            })?;
+
+            let mut blocking = 0;
+            for difference in differences {
+                let location = match &difference.column {
+                    Some(column) => format!("{}.{}", table, column),
+                    None => table.clone(),
+                };
+                let message = format!(
+                    "{} differs from the target: {}, source {}, target {}",
+                    location,
+                    difference.kind,
+                    difference.source,
+                    difference.target
+                );
+                logger.warn(message.as_str());
+                if difference.blocking {
+                    blocking += 1;
+                } else {
+                    self.summary.record_warning(category, &table, &message);
+                }
+                self.summary.record_schema_difference(difference);
+            }
+
+            if blocking > 0 {
+                let err = CustomError::SchemaMismatch {
+                    table: table.clone(),
+                    differences: blocking,
+                };
+                logger.error(err.to_string().as_str());
+                self.summary.record_failure(category, &table, &err);
+                failures.push(err);
+            }
+        }
+        logger.info("Target schema checked");
+
+        if failures.is_empty() || self.config.errors.continue_on_error {
+            Ok(())
+        } else {
+            Err(CustomError::TablesFailed(failures))
+        }
+    }
+
+    fn compare_table(
+        &self,
+        category: &str,
+        table: &str,
+        target_db: &DbConfig
+    ) -> CustomResult<Vec<SchemaDifference>> {
+        let schema = &self.config.schema;
+        let mut source_connection = self.connections.acquire_connection(&self.config.source)?;
+        let source_columns = self.get_columns(&mut source_connection, table)?;
+
+        let mut differences: Vec<SchemaDifference> = vec![];
+        let mut add = |
+            column: Option<&str>,
+            kind: &str,
+            source: &str,
+            target: &str,
+            policy: SchemaPolicy
+        | {
+            if policy == SchemaPolicy::Ignore {
+                return;
+            }
+            differences.push(SchemaDifference {
+                category: category.to_string(),
+                table: table.to_string(),
+                column: column.map(str::to_string),
+                kind: kind.to_string(),
+                source: source.to_string(),
+                target: target.to_string(),
+                blocking: policy == SchemaPolicy::Fail,
+            });
+        };
+
+        let mut target_connection = self.connections.acquire_connection(target_db)?;
+        if !self.table_exists(&mut target_connection, table, &target_db.database)? {
+            let source = format!("{} columns", source_columns.len());
+            add(None, "missing table", &source, "none", schema.missing_columns);
+            return Ok(differences);
+        }
+        let target_columns = self.get_columns(&mut target_connection, table)?;
+
+        for source_props in &source_columns {
+            let name = source_props.name.as_str();
+            let Some(target_props) = target_columns.iter().find(|props| props.name == name) else {
+                let source = &source_props.data_type;
+                add(Some(name), "missing column", source, "none", schema.missing_columns);
+                continue;
+            };
+
+            if is_narrowing(&source_props.data_type, &target_props.data_type) {
+                add(
+                    Some(name),
+                    "type narrowing",
+                    &source_props.data_type,
+                    &target_props.data_type,
+                    schema.type_narrowing
+                );
+            }
+            if source_props.is_nullable == "YES" && target_props.is_nullable == "NO" {
+                add(Some(name), "nullability", "NULL", "NOT NULL", schema.nullability);
+            }
+            if source_props.default_value != target_props.default_value {
+                add(
+                    Some(name),
+                    "default",
+                    &get_default(source_props),
+                    &get_default(target_props),
+                    schema.defaults
+                );
+            }
+        }
+
+        for target_props in &target_columns {
+            if source_columns.iter().any(|props| props.name == target_props.name) {
+                continue;
+            }
+            // Rows can't be written without a value the source doesn't have
+            if is_required(target_props) {
+                let name = Some(target_props.name.as_str());
+                let target = format!("{} NOT NULL", target_props.data_type);
+                add(name, "extra required column", "none", &target, schema.missing_columns);
+            } else {
+                let name = Some(target_props.name.as_str());
+                add(name, "extra column", "none", &target_props.data_type, schema.extra_columns);
+            }
+        }
+
+        Ok(differences)
+    }
+
+    fn table_exists(
+        &self,
+        connection: &mut PooledConn,
+        table: &str,
+        database: &str
+    ) -> CustomResult<bool> {
+        let query =
+            "SELECT COUNT(*) FROM information_schema.TABLES
+            WHERE TABLE_SCHEMA = ? AND TABLE_NAME = ?";
+
+        connection
+            .exec_first::<u64, _, _>(query, (database, table))
+            .map(|count| count.unwrap_or(0) > 0)
+            .map_err(|err| CustomError::from(err).with_table(table))
+    }
+}
+
+fn get_default(props: &ColumnProps) -> String {
+    match &props.default_value {
+        Some(value) => value.clone(),
+        None => "none".to_string(),
+    }
+}
+
+fn is_required(props: &ColumnProps) -> bool {
+    props.is_nullable == "NO" &&
+        props.default_value.is_none() &&
+        !props.extra.to_lowercase().contains("auto_increment") &&
+        !props.extra.to_lowercase().contains("generated")
+}
+
+// A column type as SHOW COLUMNS prints it, e.g. "decimal(10,2) unsigned"
+struct ColumnType {
+    base: String,
+    params: String,
+    unsigned: bool,
+}
+
+impl ColumnType {
+    fn parse(data_type: &str) -> Self {
+        let data_type = data_type.to_lowercase();
+        let base_end = data_type.find(['(', ' ']).unwrap_or(data_type.len());
+        let params = match (data_type.find('('), data_type.rfind(')')) {
+            (Some(start), Some(end)) if start < end => data_type[start + 1..end].to_string(),
+            _ => String::new(),
+        };
+
+        Self {
+            base: data_type[..base_end].to_string(),
+            params,
+            unsigned: data_type.contains("unsigned"),
+        }
+    }
+
+    fn get_number(&self, position: usize) -> Option<u64> {
+        self.params.split(',').nth(position)?.trim().parse().ok()
+    }
+
+    // Quoted enum and set members
+    fn get_members(&self) -> Vec<&str> {
+        let params = self.params.trim().trim_start_matches('\'').trim_end_matches('\'');
+        params.split("','").collect()
+    }
+}
+
+// Whether some source value wouldn't fit the target type, unknown type changes count as narrowing
+fn is_narrowing(source: &str, target: &str) -> bool {
+    let source = ColumnType::parse(source);
+    let target = ColumnType::parse(target);
+    if
+        source.base == target.base &&
+        source.params == target.params &&
+        source.unsigned == target.unsigned
+    {
+        return false;
+    }
+
+    if let (Some(source_rank), Some(target_rank)) = (
+        get_integer_rank(&source.base),
+        get_integer_rank(&target.base),
+    ) {
+        return match (source.unsigned, target.unsigned) {
+            (false, true) => true,
+            (true, false) => target_rank <= source_rank,
+            _ => target_rank < source_rank,
+        };
+    }
+    if let (Some(source_rank), Some(target_rank)) = (
+        get_float_rank(&source.base),
+        get_float_rank(&target.base),
+    ) {
+        return target_rank < source_rank || (!source.unsigned && target.unsigned);
+    }
+    if is_decimal(&source.base) && is_decimal(&target.base) {
+        let source_precision = source.get_number(0).unwrap_or(10);
+        let source_scale = source.get_number(1).unwrap_or(0);
+        let target_precision = target.get_number(0).unwrap_or(10);
+        let target_scale = target.get_number(1).unwrap_or(0);
+        return target_scale < source_scale ||
+            target_precision.saturating_sub(target_scale) <
+                source_precision.saturating_sub(source_scale) ||
+            (!source.unsigned && target.unsigned);
+    }
+    if let (Some(source_length), Some(target_length)) = (
+        get_text_length(&source),
+        get_text_length(&target),
+    ) {
+        return target_length < source_length;
+    }
+    if let (Some(source_length), Some(target_length)) = (
+        get_binary_length(&source),
+        get_binary_length(&target),
+    ) {
+        return target_length < source_length;
+    }
+    if
+        source.base == target.base &&
+        (source.base == "enum" || source.base == "set")
+    {
+        let target_members = target.get_members();
+        return source
+            .get_members()
+            .iter()
+            .any(|member| !target_members.contains(member));
+    }
+    if source.base == target.base && is_temporal(&source.base) {
+        return target.get_number(0).unwrap_or(0) < source.get_number(0).unwrap_or(0);
+    }
+
+    true
+}
+
+fn get_integer_rank(base: &str) -> Option<u8> {
+    match base {
+        "tinyint" | "bool" | "boolean" => Some(1),
+        "smallint" => Some(2),
+        "mediumint" => Some(3),
+        "int" | "integer" => Some(4),
+        "bigint" => Some(5),
+        _ => None,
+    }
+}
+
+fn get_float_rank(base: &str) -> Option<u8> {
+    match base {
+        "float" => Some(1),
+        "double" | "real" => Some(2),
+        _ => None,
+    }
+}
+
+fn is_decimal(base: &str) -> bool {
+    base == "decimal" || base == "numeric"
+}
+
+fn is_temporal(base: &str) -> bool {
+    base == "datetime" || base == "timestamp" || base == "time"
+}
+
+// Longest value in characters
+fn get_text_length(column_type: &ColumnType) -> Option<u64> {
+    match column_type.base.as_str() {
+        "char" | "varchar" => Some(column_type.get_number(0).unwrap_or(1)),
+        "tinytext" => Some(255),
+        "text" => Some(65_535),
+        "mediumtext" => Some(16_777_215),
+        "longtext" => Some(4_294_967_295),
+        _ => None,
+    }
+}
+
+// Longest value in bytes
+fn get_binary_length(column_type: &ColumnType) -> Option<u64> {
+    match column_type.base.as_str() {
+        "binary" | "varbinary" => Some(column_type.get_number(0).unwrap_or(1)),
+        "tinyblob" => Some(255),
+        "blob" => Some(65_535),
+        "mediumblob" => Some(16_777_215),
+        "longblob" => Some(4_294_967_295),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ is_narrowing, ColumnType };
+
+    #[test]
+    fn parses_base_params_and_sign() {
+        let column_type = ColumnType::parse("DECIMAL(10,2) unsigned zerofill");
+
+        assert_eq!(column_type.base, "decimal");
+        assert_eq!(column_type.params, "10,2");
+        assert!(column_type.unsigned);
+        assert_eq!(column_type.get_number(0), Some(10));
+        assert_eq!(column_type.get_number(1), Some(2));
+    }
+
+    #[test]
+    fn parses_enum_members() {
+        let column_type = ColumnType::parse("enum('new','done','a,b')");
+
+        assert_eq!(column_type.base, "enum");
+        assert_eq!(column_type.get_members(), vec!["new", "done", "a,b"]);
+    }
+
+    #[test]
+    fn same_type_is_not_narrowing() {
+        assert!(!is_narrowing("varchar(255)", "VARCHAR(255)"));
+        assert!(!is_narrowing("bigint unsigned", "bigint unsigned"));
+    }
+
+    #[test]
+    fn integer_display_width_is_ignored() {
+        assert!(!is_narrowing("int(11)", "int"));
+        assert!(!is_narrowing("int", "int(11)"));
+        assert!(!is_narrowing("tinyint(1)", "tinyint(4)"));
+    }
+
+    #[test]
+    fn integer_rank_and_sign() {
+        assert!(!is_narrowing("int", "bigint"));
+        assert!(is_narrowing("bigint", "int"));
+        // Negative values don't fit an unsigned column of any size
+        assert!(is_narrowing("int", "bigint unsigned"));
+        // Unsigned values need a wider signed column
+        assert!(is_narrowing("int unsigned", "int"));
+        assert!(!is_narrowing("int unsigned", "bigint"));
+        assert!(!is_narrowing("int unsigned", "int unsigned zerofill"));
+    }
+
+    #[test]
+    fn decimal_precision_and_scale() {
+        assert!(!is_narrowing("decimal(10,2)", "decimal(12,2)"));
+        assert!(!is_narrowing("decimal(10,2)", "decimal(11,3)"));
+        // Fewer digits after the point round the values
+        assert!(is_narrowing("decimal(10,2)", "decimal(10,1)"));
+        // Same scale but fewer digits before the point
+        assert!(is_narrowing("decimal(10,2)", "decimal(11,4)"));
+        assert!(is_narrowing("decimal(10,2)", "decimal(10,2) unsigned"));
+        // A bare decimal is decimal(10,0)
+        assert!(!is_narrowing("decimal", "decimal(10,0)"));
+    }
+
+    #[test]
+    fn text_and_binary_lengths() {
+        assert!(!is_narrowing("varchar(255)", "text"));
+        assert!(!is_narrowing("char(10)", "varchar(10)"));
+        assert!(!is_narrowing("tinytext", "varchar(255)"));
+        assert!(is_narrowing("text", "varchar(255)"));
+        assert!(is_narrowing("varchar(100)", "varchar(50)"));
+        assert!(!is_narrowing("varbinary(16)", "blob"));
+        assert!(is_narrowing("mediumblob", "blob"));
+    }
+
+    #[test]
+    fn enum_members() {
+        assert!(!is_narrowing("enum('a','b')", "enum('a','b','c')"));
+        assert!(!is_narrowing("enum('a','b')", "enum('b','a')"));
+        assert!(is_narrowing("enum('a','b','c')", "enum('a','b')"));
+        assert!(is_narrowing("set('x','y')", "set('x')"));
+    }
+
+    #[test]
+    fn temporal_precision() {
+        assert!(!is_narrowing("datetime", "datetime(3)"));
+        assert!(is_narrowing("datetime(6)", "datetime(3)"));
+    }
+
+    #[test]
+    fn unknown_changes_are_narrowing() {
+        assert!(is_narrowing("int", "varchar(255)"));
+        assert!(is_narrowing("varchar(10)", "int"));
+        assert!(is_narrowing("datetime", "date"));
+        assert!(is_narrowing("json", "text"));
+    }
+}
@@ -1,19 +1,34 @@
-use std::collections::HashMap;
+use std::{ collections::HashMap, ops::{ Deref, DerefMut }, time::Instant };
 
-use mysql::{ from_value, prelude::Queryable, Error, PooledConn, Row, Value };
+use mysql::{ from_value_opt, prelude::Queryable, Error, PooledConn, Row, Value };
 
-use crate::custom_error::{ CustomError, CustomResult };
+use crate::{
+    config::{ Config, ConflictMode },
+    custom_error::{ CustomError, CustomResult },
+    metrics,
+    retry::is_transient_mysql_error,
+    traits::decode_or_null,
+};
 
 #[derive(Debug, Clone)]
 pub struct ColumnProps {
     pub name: String,
     pub data_type: String,
+    #[allow(dead_code)]
     pub is_nullable: String,
     pub key: String,
+    #[allow(dead_code)]
     pub default_value: Option<String>,
+    #[allow(dead_code)]
     pub extra: String,
 }
 
+impl ColumnProps {
+    pub fn is_unique_key(&self) -> bool {
+        self.key == "PRI" || self.key == "UNI"
+    }
+}
+
 #[derive(Debug, mysql::prelude::FromRow)]
 pub struct FkColumnUsage {
     pub column_name: String,
@@ -21,8 +36,91 @@ pub struct FkColumnUsage {
     pub referenced_column_name: String,
 }
 
-type ColumnData = HashMap<String, (ColumnProps, Value)>;
+#[derive(Debug, Clone)]
+pub struct TableData {
+    pub columns: Vec<ColumnProps>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+// WHERE clause of a table query, plus the limit when no parent table narrows the table down.
+// Only selects take the limit, a purge clears the whole scope
+pub struct TableScope {
+    pub filter: String,
+    pub limit: Option<String>,
+}
+
+impl TableScope {
+    pub fn get_select_query(&self, table: &str, select_column: Option<String>) -> String {
+        let limit = self.limit.as_deref().unwrap_or_default();
+        match select_column {
+            None => format!("SELECT * FROM {}{}{}", table, self.filter, limit),
+            // MySQL doesn't take LIMIT in an IN (...) subquery, a derived table does
+            Some(column) if self.limit.is_some() =>
+                format!(
+                    "SELECT {} FROM (\nSELECT {} FROM {}{}{}\n) AS limited",
+                    column,
+                    column,
+                    table,
+                    self.filter,
+                    limit
+                ),
+            Some(column) => format!("SELECT {} FROM {}{}", column, table, self.filter),
+        }
+    }
+
+    pub fn get_delete_query(&self, table: &str) -> String {
+        format!("DELETE FROM {}{}", table, self.filter)
+    }
+}
+
+// Bounds the SELECTs of one table on a pooled connection and lifts the bound on drop,
+// so whoever borrows the connection next doesn't inherit this table's limit
+pub struct StatementTimeoutGuard<'conn> {
+    connection: &'conn mut PooledConn,
+    is_set: bool,
+}
+
+impl<'conn> StatementTimeoutGuard<'conn> {
+    // MAX_EXECUTION_TIME only bounds SELECTs, no timeout leaves the session as it is
+    pub fn new(connection: &'conn mut PooledConn, timeout_ms: Option<u64>) -> CustomResult<Self> {
+        if let Some(timeout_ms) = timeout_ms {
+            let query = format!("SET SESSION MAX_EXECUTION_TIME = {};", timeout_ms);
+            connection.query_drop(query).map_err(CustomError::from)?;
+        }
+
+        Ok(Self { connection, is_set: timeout_ms.is_some() })
+    }
+}
+
+impl Deref for StatementTimeoutGuard<'_> {
+    type Target = PooledConn;
+
+    fn deref(&self) -> &PooledConn {
+        self.connection
+    }
+}
+
+impl DerefMut for StatementTimeoutGuard<'_> {
+    fn deref_mut(&mut self) -> &mut PooledConn {
+        self.connection
+    }
+}
+
+impl Drop for StatementTimeoutGuard<'_> {
+    fn drop(&mut self) {
+        if !self.is_set {
+            return;
+        }
+        if let Err(err) = self.connection.query_drop("SET SESSION MAX_EXECUTION_TIME = DEFAULT") {
+            let logger = crate::logger::Logger::new();
+            logger.error(format!("Can't reset MAX_EXECUTION_TIME: {}", err).as_str());
+        }
+    }
+}
+
 pub trait TableQueryGenerator {
+    fn get_config(&self) -> &Config;
+
     fn get_columns(
         &self,
         connection: &mut PooledConn,
@@ -34,122 +132,227 @@ pub trait TableQueryGenerator {
             .query_map(
                 column_query,
                 |(field_value, type_value, null_value, key_value, default_value, extra)| {
-                    let props = ColumnProps {
+                    ColumnProps {
                         name: field_value,
                         data_type: type_value,
                         is_nullable: null_value,
                         key: key_value,
                         default_value,
                         extra,
-                    };
-
-                    props
+                    }
                 }
-            )
-            .map_err(|err| CustomError::DbQueryExecution(err.to_string()));
+            );
 
         match raw_results {
             Ok(results) => Ok(results),
-            Err(_) => Err(CustomError::DbTableStructure),
+            Err(err) => Err(get_table_structure_error(table, err)),
         }
     }
 
+    // Limit of a root table, ordered by the primary key so the table query
+    // and the subqueries of its children keep the same rows
+    fn get_limit_clause(&self, table: &str, columns: &[ColumnProps]) -> Option<String> {
+        let config = self.get_config();
+        let limit = config.business.limit.filter(|_| !config.sample.enabled)?;
+        let keys: Vec<String> = columns
+            .iter()
+            .filter(|props| props.key == "PRI")
+            .map(|props| format!("`{}`", props.name))
+            .collect();
+        if keys.is_empty() {
+            let logger = crate::logger::Logger::new();
+            let message = format!("Table {} has no primary key, limit keeps arbitrary rows", table);
+            logger.warn(message.as_str());
+            return Some(format!("\nLIMIT {}", limit));
+        }
+
+        Some(format!("\nORDER BY {}\nLIMIT {}", keys.join(", "), limit))
+    }
+
+    // Wrapping the select keeps its LIMIT in effect
+    fn count_rows(
+        &self,
+        connection: &mut PooledConn,
+        table: &str,
+        select_query: &str
+    ) -> CustomResult<u64> {
+        let query = format!("SELECT COUNT(*) FROM (\n{}\n) AS scoped", select_query);
+
+        connection
+            .query_first::<u64, _>(&query)
+            .map(|count| count.unwrap_or(0))
+            .map_err(|err| CustomError::from(err).with_table(table).with_sql(&query))
+    }
+
     fn get_data(
         &self,
         connection: &mut PooledConn,
         table: &str,
         query: &str
-    ) -> CustomResult<Vec<ColumnData>> {
+    ) -> CustomResult<TableData> {
         let columns = self.get_columns(connection, table)?;
-        let data: Vec<HashMap<String, (ColumnProps, mysql::Value)>> = connection
-            .query_map(query, |row: Row| {
-                let mut map: HashMap<String, (ColumnProps, mysql::Value)> = HashMap::new();
-                for (index, props) in columns.iter().enumerate() {
-                    map.insert(props.name.clone(), (props.clone(), row.get(index).unwrap()));
-                }
-                map
-            })
-            .unwrap();
+        let started = Instant::now();
+        let raw_rows: Vec<Vec<Option<Value>>> = connection
+            .query_map(query, |row: Row| row.unwrap_raw())
+            .map_err(|err| CustomError::from(err).with_table(table).with_sql(query))?;
+        metrics::observe_query("select", started.elapsed());
 
-        Ok(data)
+        let lenient = self.get_config().extract.lenient_decoding;
+        let mut rows: Vec<Vec<Value>> = vec![];
+        for (row_index, raw_row) in raw_rows.into_iter().enumerate() {
+            let mut row: Vec<Value> = vec![];
+            for (props, value) in columns.iter().zip(raw_row) {
+                let value = value.ok_or_else(|| {
+                    get_decoding_error(table, props, row_index, "value is missing from the row")
+                });
+                row.push(decode_or_null(lenient, value, Value::NULL)?);
+            }
+            rows.push(row);
+        }
+
+        Ok(TableData { columns, rows })
     }
 
-    fn generate_insert_query(&self, data: &Vec<ColumnData>, table: &str) -> CustomResult<String> {
+    fn generate_insert_query(
+        &self,
+        columns: &[ColumnProps],
+        rows: &[Vec<Value>],
+        table: &str,
+        conflict_mode: ConflictMode
+    ) -> CustomResult<String> {
         let logger = crate::logger::Logger::new();
         logger.debug(format!("Generating insert statements for table: {}", table).as_str());
         let mut result = String::new();
 
-        let mut columns_populated = false;
-        let mut columns: Vec<String> = vec![];
+        let lenient = self.get_config().extract.lenient_decoding;
         let mut values_as_strings: Vec<String> = vec![];
-
-        for row in data {
-            if !columns_populated {
-                columns = row
-                    .keys()
-                    .map(|key| key.to_string())
-                    .collect();
-                columns_populated = true;
-            }
-            let mut values_as_str = String::new();
-            for (index, column) in columns.iter().enumerate() {
-                let params = row.get(column.as_str()).unwrap();
-                let value_str = self.parse_mysql_value_to_string(&params.0, &params.1);
-                values_as_str.push_str(value_str.as_str());
-
-                if index < columns.len() - 1 {
-                    values_as_str.push_str(", ");
-                }
+        for (row_index, row) in rows.iter().enumerate() {
+            let mut values: Vec<String> = vec![];
+            for (props, value) in columns.iter().zip(row.iter()) {
+                let value = self
+                    .parse_mysql_value_to_string(props, value)
+                    .map_err(|reason| get_decoding_error(table, props, row_index, reason));
+                values.push(decode_or_null(lenient, value, "NULL".to_string())?);
             }
 
-            values_as_strings.push(values_as_str);
+            values_as_strings.push(values.join(", "));
         }
         logger.debug(format!("Generated insert statements for table: {}", table).as_str());
 
-        columns = columns
+        let update_columns = self.get_update_columns(columns);
+        let column_names: Vec<String> = columns
             .iter()
-            .map(|column| format!("`{}`", column))
+            .map(|props| format!("`{}`", props.name))
             .collect();
 
         let insert_query = format!(
-            "INSERT INTO\n{} ({})\nVALUES\n({});",
+            "{}\n{} ({})\nVALUES\n({}){};",
+            self.get_insert_statement(conflict_mode),
             table,
-            columns.join(", "),
-            values_as_strings.join("), \n(")
+            column_names.join(", "),
+            values_as_strings.join("), \n("),
+            self.get_conflict_clause(conflict_mode, &update_columns)
         );
 
-        if columns.len() > 0 {
+        if !values_as_strings.is_empty() {
             result.push_str(insert_query.as_str());
-            result.push_str("\n");
+            result.push('\n');
         }
 
         Ok(result)
     }
 
-    fn parse_mysql_value_to_string(&self, column_pros: &ColumnProps, value: &Value) -> String {
+    fn get_prepared_insert_query(
+        &self,
+        columns: &[ColumnProps],
+        row_count: usize,
+        table: &str,
+        conflict_mode: ConflictMode
+    ) -> String {
+        let update_columns = self.get_update_columns(columns);
+        let column_names: Vec<String> = columns
+            .iter()
+            .map(|props| format!("`{}`", props.name))
+            .collect();
+        let placeholders = format!("({})", vec!["?"; columns.len()].join(", "));
+
+        format!(
+            "{} {} ({}) VALUES {}{}",
+            self.get_insert_statement(conflict_mode),
+            table,
+            column_names.join(", "),
+            vec![placeholders; row_count].join(", "),
+            self.get_conflict_clause(conflict_mode, &update_columns)
+        )
+    }
+
+    fn get_update_columns(&self, columns: &[ColumnProps]) -> Vec<String> {
+        let update_columns: Vec<String> = columns
+            .iter()
+            .filter(|props| !props.is_unique_key())
+            .map(|props| props.name.clone())
+            .collect();
+
+        if update_columns.is_empty() {
+            // Nothing but keys to update, a no-op assignment still swallows the duplicate
+            columns
+                .iter()
+                .take(1)
+                .map(|props| props.name.clone())
+                .collect()
+        } else {
+            update_columns
+        }
+    }
+
+    fn get_insert_statement(&self, conflict_mode: ConflictMode) -> &'static str {
+        match conflict_mode {
+            ConflictMode::Fail | ConflictMode::Upsert => "INSERT INTO",
+            ConflictMode::Ignore => "INSERT IGNORE INTO",
+            ConflictMode::Replace => "REPLACE INTO",
+        }
+    }
+
+    fn get_conflict_clause(
+        &self,
+        conflict_mode: ConflictMode,
+        update_columns: &[String]
+    ) -> String {
+        if conflict_mode != ConflictMode::Upsert {
+            return String::new();
+        }
+
+        let assignments: Vec<String> = update_columns
+            .iter()
+            .map(|column| format!("`{}` = VALUES(`{}`)", column, column))
+            .collect();
+
+        format!("\nON DUPLICATE KEY UPDATE\n{}", assignments.join(", "))
+    }
+
+    // The error only names what went wrong, the value itself is data and stays out of logs
+    fn parse_mysql_value_to_string(
+        &self,
+        column_pros: &ColumnProps,
+        value: &Value
+    ) -> Result<String, &'static str> {
         match value {
-            mysql::Value::NULL => "NULL".to_string(),
-            mysql::Value::Bytes(bytes) => {
-                if column_pros.data_type.starts_with("binary") {
-                    let hex_string: String = bytes
-                        .iter()
-                        .map(|b| format!("{:02X}", b))
-                        .collect();
-                    format!("X'{}'", hex_string)
-                } else {
-                    let mut value = from_value::<String>(value.clone());
-                    if value.contains('\'') {
-                        value = value.replace('\'', "\\'");
-                    }
-                    format!("'{}'", value)
-                }
+            mysql::Value::NULL => Ok("NULL".to_string()),
+            mysql::Value::Bytes(bytes) if column_pros.data_type.starts_with("binary") => {
+                let hex_string: String = bytes
+                    .iter()
+                    .map(|b| format!("{:02X}", b))
+                    .collect();
+                Ok(format!("X'{}'", hex_string))
             }
             _ => {
-                let mut value = from_value::<String>(value.clone());
+                let mut value = from_value_opt::<String>(value.clone()).map_err(
+                    |_| "value can't be converted to text"
+                )?;
                 if value.contains('\'') {
                     value = value.replace('\'', "\\'");
                 }
-                format!("'{}'", value)
+                Ok(format!("'{}'", value))
             }
         }
     }
@@ -190,7 +393,260 @@ pub trait TableQueryGenerator {
 
         match raw_results {
             Ok(results) => Ok(results),
-            Err(_) => Err(CustomError::DbTableStructure),
+            Err(err) => Err(get_table_structure_error(table, err)),
+        }
+    }
+
+    // Orders tables so that referenced tables come before the tables referencing them.
+    // Only references between the given tables are taken into account.
+    fn sort_by_dependencies(
+        &self,
+        connection: &mut PooledConn,
+        tables: &[String],
+        database: &String
+    ) -> CustomResult<Vec<String>> {
+        let mut parents: HashMap<String, Vec<String>> = HashMap::new();
+        for table in tables {
+            let references = self.get_table_references(connection, table, database)?;
+            let table_parents = references
+                .into_iter()
+                .map(|reference| reference.referenced_table_name)
+                .filter(|parent| parent != table && tables.contains(parent))
+                .collect();
+            parents.insert(table.clone(), table_parents);
+        }
+
+        let mut sorted: Vec<String> = vec![];
+        while sorted.len() < tables.len() {
+            let next = tables
+                .iter()
+                .find(|table| {
+                    !sorted.contains(table) &&
+                        parents[table.as_str()].iter().all(|parent| sorted.contains(parent))
+                });
+
+            match next {
+                Some(table) => sorted.push(table.clone()),
+                None => {
+                    let logger = crate::logger::Logger::new();
+                    logger.warn("Circular table references found, keeping configured order");
+                    for table in tables {
+                        if !sorted.contains(table) {
+                            sorted.push(table.clone());
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(sorted)
+    }
+}
+
+// Transient errors stay retryable, anything else means the table can't be inspected
+fn get_table_structure_error(table: &str, err: Error) -> CustomError {
+    if is_transient_mysql_error(&err) {
+        CustomError::from(err).with_table(table)
+    } else {
+        CustomError::DbTableStructure { table: table.to_string(), source: Box::new(err) }
+    }
+}
+
+pub fn get_decoding_error(
+    table: &str,
+    props: &ColumnProps,
+    row_index: usize,
+    reason: &'static str
+) -> CustomError {
+    CustomError::DbValueDecoding {
+        table: table.to_string(),
+        column: props.name.clone(),
+        row: row_index + 1,
+        sql_type: props.data_type.clone(),
+        source: reason.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mysql::Value;
+
+    use crate::config::{ get_test_config, Config, ConflictMode };
+
+    use super::{ ColumnProps, TableQueryGenerator, TableScope };
+
+    struct Generator {
+        config: Config,
+    }
+
+    impl TableQueryGenerator for Generator {
+        fn get_config(&self) -> &Config {
+            &self.config
         }
     }
+
+    fn get_column(name: &str, key: &str) -> ColumnProps {
+        ColumnProps {
+            name: name.to_string(),
+            data_type: "varchar(255)".to_string(),
+            is_nullable: "YES".to_string(),
+            key: key.to_string(),
+            default_value: None,
+            extra: String::new(),
+        }
+    }
+
+    fn get_bytes(value: &str) -> Value {
+        Value::Bytes(value.as_bytes().to_vec())
+    }
+
+    fn generate(columns: &[ColumnProps], conflict_mode: ConflictMode) -> String {
+        let generator = Generator { config: get_test_config() };
+        // The text protocol returns every value as bytes
+        let rows = vec![
+            vec![get_bytes("1"), get_bytes("o'k"), Value::NULL],
+            vec![get_bytes("2"), get_bytes("b"), get_bytes("x")]
+        ];
+
+        generator.generate_insert_query(columns, &rows, "items", conflict_mode).unwrap()
+    }
+
+    fn get_columns() -> Vec<ColumnProps> {
+        vec![get_column("id", "PRI"), get_column("code", "UNI"), get_column("name", "")]
+    }
+
+    #[test]
+    fn fail_renders_a_plain_insert() {
+        assert_eq!(
+            generate(&get_columns(), ConflictMode::Fail),
+            "INSERT INTO\nitems (`id`, `code`, `name`)\nVALUES\n('1', 'o\\'k', NULL), \n\
+             ('2', 'b', 'x');\n"
+        );
+    }
+
+    #[test]
+    fn ignore_renders_insert_ignore() {
+        assert!(
+            generate(&get_columns(), ConflictMode::Ignore).starts_with(
+                "INSERT IGNORE INTO\nitems (`id`, `code`, `name`)\nVALUES\n"
+            )
+        );
+    }
+
+    #[test]
+    fn replace_renders_replace_into() {
+        let query = generate(&get_columns(), ConflictMode::Replace);
+
+        assert!(query.starts_with("REPLACE INTO\nitems (`id`, `code`, `name`)\nVALUES\n"));
+        assert!(!query.contains("ON DUPLICATE KEY"));
+    }
+
+    #[test]
+    fn upsert_updates_the_columns_that_are_not_keys() {
+        assert!(
+            generate(&get_columns(), ConflictMode::Upsert).ends_with(
+                "('2', 'b', 'x')\nON DUPLICATE KEY UPDATE\n`name` = VALUES(`name`);\n"
+            )
+        );
+    }
+
+    #[test]
+    fn upsert_of_keys_only_keeps_a_no_op_assignment() {
+        let columns = vec![get_column("id", "PRI"), get_column("code", "UNI")];
+        let generator = Generator { config: get_test_config() };
+        let rows = vec![vec![get_bytes("1"), get_bytes("a")]];
+
+        let query = generator
+            .generate_insert_query(&columns, &rows, "items", ConflictMode::Upsert)
+            .unwrap();
+
+        assert!(query.ends_with("ON DUPLICATE KEY UPDATE\n`id` = VALUES(`id`);\n"));
+    }
+
+    #[test]
+    fn prepared_insert_uses_the_same_conflict_handling() {
+        let generator = Generator { config: get_test_config() };
+
+        assert_eq!(
+            generator.get_prepared_insert_query(&get_columns(), 2, "items", ConflictMode::Upsert),
+            "INSERT INTO items (`id`, `code`, `name`) VALUES (?, ?, ?), (?, ?, ?)\n\
+             ON DUPLICATE KEY UPDATE\n`name` = VALUES(`name`)"
+        );
+    }
+
+    #[test]
+    fn no_rows_render_nothing() {
+        let generator = Generator { config: get_test_config() };
+
+        let query = generator
+            .generate_insert_query(&get_columns(), &[], "items", ConflictMode::Fail)
+            .unwrap();
+
+        assert_eq!(query, "");
+    }
+
+    fn get_limited_config() -> Config {
+        let mut config = get_test_config();
+        config.business.limit = Some(10);
+        config
+    }
+
+    #[test]
+    fn limited_root_is_wrapped_in_a_derived_table_for_subqueries() {
+        let scope = TableScope {
+            filter: " WHERE study_id = 1".to_string(),
+            limit: Some("\nORDER BY `id`\nLIMIT 10".to_string()),
+        };
+
+        assert_eq!(
+            scope.get_select_query("issues", None),
+            "SELECT * FROM issues WHERE study_id = 1\nORDER BY `id`\nLIMIT 10"
+        );
+        assert_eq!(
+            scope.get_select_query("issues", Some("id".to_string())),
+            "SELECT id FROM (\nSELECT id FROM issues WHERE study_id = 1\nORDER BY `id`\nLIMIT 10\n\
+             ) AS limited"
+        );
+    }
+
+    #[test]
+    fn unlimited_subquery_selects_the_column_directly() {
+        let scope = TableScope { filter: " WHERE study_id = 1".to_string(), limit: None };
+
+        assert_eq!(
+            scope.get_select_query("issues", Some("id".to_string())),
+            "SELECT id FROM issues WHERE study_id = 1"
+        );
+        assert_eq!(scope.get_delete_query("issues"), "DELETE FROM issues WHERE study_id = 1");
+    }
+
+    #[test]
+    fn limit_is_ordered_by_the_primary_key() {
+        let generator = Generator { config: get_limited_config() };
+        let columns = vec![get_column("id", "PRI"), get_column("run", "PRI"), get_column("a", "")];
+
+        assert_eq!(
+            generator.get_limit_clause("items", &columns).as_deref(),
+            Some("\nORDER BY `id`, `run`\nLIMIT 10")
+        );
+    }
+
+    #[test]
+    fn table_without_primary_key_takes_a_plain_limit() {
+        let generator = Generator { config: get_limited_config() };
+        let columns = vec![get_column("code", "UNI"), get_column("name", "")];
+
+        assert_eq!(generator.get_limit_clause("items", &columns).as_deref(), Some("\nLIMIT 10"));
+    }
+
+    #[test]
+    fn sampling_or_no_limit_leaves_the_query_unlimited() {
+        let generator = Generator { config: get_test_config() };
+        assert_eq!(generator.get_limit_clause("items", &get_columns()), None);
+
+        let mut config = get_limited_config();
+        config.sample.enabled = true;
+        let generator = Generator { config };
+        assert_eq!(generator.get_limit_clause("items", &get_columns()), None);
+    }
 }
@@ -0,0 +1,394 @@
+use std::{ collections::{ hash_map::DefaultHasher, HashMap }, hash::{ Hash, Hasher } };
+
+use mysql::{ prelude::Queryable, PooledConn, Value };
+
+use crate::{
+    cancellation::check_cancelled,
+    config::{ Config, DbConfig },
+    custom_error::{ CustomError, CustomResult },
+    logger::LoggerTrait,
+    retry::with_retry,
+    summary::{ ChecksumCheck, RowCountCheck, RunSummary },
+    traits::{ InsertQueries, TableQuery, BATCH_TABLES, DOUBLE_STAGED_TABLES },
+};
+
+use super::{
+    batch_table_query_provider::BatchTableQueryProvider,
+    db::ConnectionManager,
+    double_staged_table_query_provider::DoubleStagedTableQueryProvider,
+    traits::{ StatementTimeoutGuard, TableQueryGenerator },
+};
+
+// Differing keys kept for the run summary, the log lists all of them
+const MAX_REPORTED_KEYS: usize = 20;
+
+// Hashes of one side of a table, row hashes are only kept when keys are reported
+struct TableDigest {
+    checksum: u64,
+    rows: Vec<(String, u64)>,
+}
+
+// Re-runs the scoped select of every loaded table on source and target and compares them
+pub struct LoadVerifier<'config> {
+    pub config: &'config Config,
+    pub summary: &'config RunSummary,
+    pub connections: &'config ConnectionManager,
+}
+
+impl<'config> LoggerTrait for LoadVerifier<'config> {}
+impl<'config> TableQueryGenerator for LoadVerifier<'config> {
+    fn get_config(&self) -> &Config {
+        self.config
+    }
+}
+
+impl LoadVerifier<'_> {
+    pub fn verify(&self, data: &InsertQueries) -> CustomResult<()> {
+        let logger = self.get_logger();
+        let Some(target_db) = &self.config.target_db else {
+            logger.warn("No target DB configured, the load is not verified");
+            return Ok(());
+        };
+        logger.info("Verifying loaded tables");
+        let mut mismatches: Vec<CustomError> = vec![];
+
+        let batch_provider = BatchTableQueryProvider { config: self.config };
+        for table_query in &data.batch_tables {
+            let table = &table_query.table;
+            let timeout_ms = self.config.timeout.get_statement_timeout(table);
+            mismatches.extend(
+                self.verify_table(BATCH_TABLES, table_query, target_db, timeout_ms, |connection| {
+                    batch_provider.get_select_query(connection, table, None)
+                })?
+            );
+        }
+
+        let double_staged_provider = DoubleStagedTableQueryProvider { config: self.config };
+        for table_prefix in &self.config.tables.double_partitioned_tables {
+            let table = double_staged_provider.get_table_name(table_prefix);
+            let Some(table_query) = data.double_staged_tables
+                .iter()
+                .find(|table_query| table_query.table == table) else {
+                continue;
+            };
+            let timeout_ms = self.config.timeout.get_partitioned_statement_timeout(
+                &table,
+                table_prefix
+            );
+            mismatches.extend(
+                self.verify_table(
+                    DOUBLE_STAGED_TABLES,
+                    table_query,
+                    target_db,
+                    timeout_ms,
+                    |connection| {
+                        double_staged_provider.get_select_query(connection, table_prefix, None)
+                    }
+                )?
+            );
+        }
+        logger.info("Loaded tables verified");
+
+        if mismatches.is_empty() || self.config.errors.continue_on_error {
+            Ok(())
+        } else {
+            Err(CustomError::TablesFailed(mismatches))
+        }
+    }
+
+    // Returns the mismatches that have to fail the run
+    fn verify_table<F>(
+        &self,
+        category: &str,
+        table_query: &TableQuery,
+        target_db: &DbConfig,
+        timeout_ms: Option<u64>,
+        get_select_query: F
+    ) -> CustomResult<Vec<CustomError>>
+        where F: Fn(&mut PooledConn) -> CustomResult<String>
+    {
+        let table = &table_query.table;
+        let mut mismatches: Vec<CustomError> = vec![];
+        // Tables that failed or were skipped have nothing in the target to compare
+        if !self.summary.is_loaded(category, table) {
+            return Ok(mismatches);
+        }
+
+        if self.config.verify.row_counts {
+            let operation = format!("Counting rows of table {}", table);
+            let result = self.run_on_both(
+                &operation,
+                target_db,
+                timeout_ms,
+                &get_select_query,
+                |connection, select_query| self.count_rows(connection, table, select_query)
+            );
+            match result {
+                Ok((source_rows, target_rows)) => {
+                    let check = RowCountCheck {
+                        category: category.to_string(),
+                        table: table.clone(),
+                        extracted: table_query.rows,
+                        source_rows,
+                        target_rows,
+                    };
+                    mismatches.extend(self.settle_row_counts(check));
+                }
+                Err(err) => {
+                    return self.summary
+                        .continue_after(self.config, category, table, err)
+                        .map(|_| mismatches);
+                }
+            }
+        }
+
+        if self.config.verify.checksums {
+            let operation = format!("Hashing rows of table {}", table);
+            let result = self.run_on_both(
+                &operation,
+                target_db,
+                timeout_ms,
+                &get_select_query,
+                |connection, select_query| self.get_digest(connection, table, select_query)
+            );
+            match result {
+                Ok((Some(source), Some(target))) => {
+                    mismatches.extend(self.settle_checksums(category, table, &source, &target));
+                }
+                Ok(_) => {
+                    let message = format!("Table {} has no primary key, checksum skipped", table);
+                    self.get_logger().warn(message.as_str());
+                    self.summary.record_warning(category, table, &message);
+                }
+                Err(err) => {
+                    return self.summary
+                        .continue_after(self.config, category, table, err)
+                        .map(|_| mismatches);
+                }
+            }
+        }
+
+        Ok(mismatches)
+    }
+
+    // Scope filters follow the source foreign keys, so the select is built there
+    // and the same text runs on the target
+    fn run_on_both<T, F, C>(
+        &self,
+        operation: &str,
+        target_db: &DbConfig,
+        timeout_ms: Option<u64>,
+        get_select_query: &F,
+        check: C
+    ) -> CustomResult<(T, T)>
+        where
+            F: Fn(&mut PooledConn) -> CustomResult<String>,
+            C: Fn(&mut PooledConn, &str) -> CustomResult<T>
+    {
+        check_cancelled()?;
+        with_retry(&self.config.retry, operation, |_| {
+            let mut source = self.connections.acquire_connection(&self.config.source)?;
+            let mut source = StatementTimeoutGuard::new(&mut source, timeout_ms)?;
+            let select_query = get_select_query(&mut source)?;
+            let source_result = check(&mut source, &select_query)?;
+
+            let mut target = self.connections.acquire_connection(target_db)?;
+            let mut target = StatementTimeoutGuard::new(&mut target, timeout_ms)?;
+            let target_result = check(&mut target, &select_query)?;
+
+            Ok((source_result, target_result))
+        })
+    }
+
+    // Streams the scoped rows in primary key order, None when the table has no primary key
+    fn get_digest(
+        &self,
+        connection: &mut PooledConn,
+        table: &str,
+        select_query: &str
+    ) -> CustomResult<Option<TableDigest>> {
+        let columns = self.get_columns(connection, table)?;
+        let mut key_columns: Vec<String> = columns
+            .iter()
+            .filter(|props| props.key == "PRI")
+            .map(|props| format!("`{}`", props.name))
+            .collect();
+        if key_columns.is_empty() {
+            return Ok(None);
+        }
+        // Sorted names hash the same whatever the column order of each side
+        let mut column_names: Vec<String> = columns
+            .iter()
+            .map(|props| format!("`{}`", props.name))
+            .collect();
+        column_names.sort();
+        key_columns.sort();
+        let key_positions: Vec<usize> = key_columns
+            .iter()
+            .filter_map(|key| column_names.iter().position(|name| name == key))
+            .collect();
+
+        let query = format!(
+            "SELECT {} FROM (\n{}\n) AS scoped ORDER BY {}",
+            column_names.join(", "),
+            select_query,
+            key_columns.join(", ")
+        );
+        let to_error = |err: mysql::Error| {
+            CustomError::from(err).with_table(table).with_sql(&query)
+        };
+        let report_keys = self.config.verify.report_keys;
+        let mut table_hasher = DefaultHasher::new();
+        let mut rows: Vec<(String, u64)> = vec![];
+
+        for row in connection.query_iter(&query).map_err(to_error)? {
+            let values = row.map_err(to_error)?.unwrap();
+            let mut row_hasher = DefaultHasher::new();
+            for value in &values {
+                hash_value(&mut row_hasher, value);
+            }
+            let row_hash = row_hasher.finish();
+            row_hash.hash(&mut table_hasher);
+
+            if report_keys {
+                rows.push((get_key(&values, &key_positions), row_hash));
+            }
+        }
+
+        Ok(Some(TableDigest { checksum: table_hasher.finish(), rows }))
+    }
+
+    fn settle_row_counts(&self, check: RowCountCheck) -> Option<CustomError> {
+        let is_match = check.is_match();
+        let err = CustomError::RowCountMismatch {
+            table: check.table.clone(),
+            extracted: check.extracted,
+            source_rows: check.source_rows,
+            target_rows: check.target_rows,
+        };
+        let category = check.category.clone();
+        let table = check.table.clone();
+        self.summary.record_row_count(check);
+
+        if is_match { None } else { self.settle_mismatch(&category, &table, err) }
+    }
+
+    fn settle_checksums(
+        &self,
+        category: &str,
+        table: &str,
+        source: &TableDigest,
+        target: &TableDigest
+    ) -> Option<CustomError> {
+        let differing_keys = if self.config.verify.report_keys {
+            let keys = get_differing_keys(source, target);
+            if !keys.is_empty() {
+                let message = format!(
+                    "Rows of table {} differ for keys: {}",
+                    table,
+                    keys.join("; ")
+                );
+                self.get_logger().warn(message.as_str());
+            }
+            Some(keys)
+        } else {
+            None
+        };
+        let check = ChecksumCheck {
+            category: category.to_string(),
+            table: table.to_string(),
+            source_checksum: source.checksum,
+            target_checksum: target.checksum,
+            differing_rows: differing_keys.as_ref().map(|keys| keys.len()),
+            differing_keys: differing_keys
+                .unwrap_or_default()
+                .into_iter()
+                .take(MAX_REPORTED_KEYS)
+                .collect(),
+        };
+        let is_match = check.is_match();
+        self.summary.record_checksum(check);
+
+        if is_match {
+            None
+        } else {
+            let err = CustomError::ChecksumMismatch { table: table.to_string() };
+            self.settle_mismatch(category, table, err)
+        }
+    }
+
+    fn settle_mismatch(
+        &self,
+        category: &str,
+        table: &str,
+        err: CustomError
+    ) -> Option<CustomError> {
+        self.get_logger().warn(err.to_string().as_str());
+        if !self.config.verify.fail_on_mismatch {
+            self.summary.record_warning(category, table, &err.to_string());
+            return None;
+        }
+        self.summary.record_failure(category, table, &err);
+
+        Some(err)
+    }
+}
+
+// Tags every value with its kind so e.g. NULL and an empty string hash differently.
+// DefaultHasher output may change between Rust releases, which is fine since both sides
+// are hashed by the same binary.
+fn hash_value<H: Hasher>(hasher: &mut H, value: &Value) {
+    match value {
+        Value::NULL => (0u8).hash(hasher),
+        Value::Bytes(bytes) => (1u8, bytes).hash(hasher),
+        Value::Int(value) => (2u8, value).hash(hasher),
+        Value::UInt(value) => (3u8, value).hash(hasher),
+        Value::Float(value) => (4u8, value.to_bits()).hash(hasher),
+        Value::Double(value) => (5u8, value.to_bits()).hash(hasher),
+        Value::Date(year, month, day, hour, minute, second, micros) =>
+            (6u8, year, month, day, hour, minute, second, micros).hash(hasher),
+        Value::Time(negative, days, hours, minutes, seconds, micros) =>
+            (7u8, negative, days, hours, minutes, seconds, micros).hash(hasher),
+    }
+}
+
+fn get_key(values: &[Value], key_positions: &[usize]) -> String {
+    let parts: Vec<String> = key_positions
+        .iter()
+        .map(|position| {
+            match values.get(*position) {
+                Some(Value::Bytes(bytes)) => String::from_utf8_lossy(bytes).to_string(),
+                Some(value) => value.as_sql(false),
+                None => String::new(),
+            }
+        })
+        .collect();
+
+    parts.join(", ")
+}
+
+// Keys whose row hash differs or that only one side has, in source order
+fn get_differing_keys(source: &TableDigest, target: &TableDigest) -> Vec<String> {
+    let target_rows: HashMap<&String, u64> = target.rows
+        .iter()
+        .map(|(key, hash)| (key, *hash))
+        .collect();
+    let source_keys: HashMap<&String, u64> = source.rows
+        .iter()
+        .map(|(key, hash)| (key, *hash))
+        .collect();
+
+    let mut keys: Vec<String> = source.rows
+        .iter()
+        .filter(|(key, hash)| target_rows.get(key) != Some(hash))
+        .map(|(key, _)| key.clone())
+        .collect();
+    keys.extend(
+        target.rows
+            .iter()
+            .filter(|(key, _)| !source_keys.contains_key(key))
+            .map(|(key, _)| key.clone())
+    );
+
+    keys
+}
@@ -0,0 +1,111 @@
+use crate::{
+    config::{ Config, ConflictMode },
+    traits::{ BATCH_TABLES, DOUBLE_STAGED_TABLES, REDSHIFT_TABLES },
+};
+
+// What a run would do with one table, built without fetching any rows
+#[derive(Debug, Clone)]
+pub struct TablePlan {
+    pub category: String,
+    pub table: String,
+    pub query: String,
+    // EXPLAIN estimates, None when the database gives none
+    pub estimated_rows: Option<u64>,
+    pub estimated_bytes: Option<u64>,
+    // Only set when the plan runs with counts
+    pub counted_rows: Option<u64>,
+    pub actions: Vec<String>,
+}
+
+// Describes what the load and the output files would do with a table
+pub fn get_target_actions(
+    config: &Config,
+    category: &str,
+    conflict_mode: ConflictMode
+) -> Vec<String> {
+    let mut actions: Vec<String> = vec![];
+    let file_name = match category {
+        BATCH_TABLES => "batch_tables.sql",
+        DOUBLE_STAGED_TABLES => "double_staged_tables.sql",
+        REDSHIFT_TABLES => "redshift_tables.sql",
+        _ => "triple_staged_tables.sql",
+    };
+
+    if config.target_path.write_files {
+        actions.push(format!("write {}/{}", config.target_path.path, file_name));
+    }
+
+    match &config.target_db {
+        // Redshift tables are only written to files
+        Some(_) if category == REDSHIFT_TABLES => {}
+        Some(target_db) => {
+            if config.schema.check {
+                actions.push(format!("compare columns with {}", target_db.database));
+            }
+            if config.insert.purge_before_load {
+                actions.push(format!("delete scoped rows from {}", target_db.database));
+            }
+            actions.push(
+                format!(
+                    "load into {} with {:?} in {:?} mode, {:?} on conflict",
+                    target_db.database,
+                    config.load.method,
+                    config.load.mode,
+                    conflict_mode
+                )
+            );
+            if config.verify.row_counts {
+                actions.push("verify row counts".to_string());
+            }
+            if config.verify.checksums {
+                actions.push("verify checksums".to_string());
+            }
+        }
+        None => {}
+    }
+
+    actions
+}
+
+pub fn print_plan(plans: &[TablePlan]) {
+    let estimated_rows: u64 = plans
+        .iter()
+        .filter_map(|plan| plan.estimated_rows)
+        .sum();
+    let estimated_bytes: u64 = plans
+        .iter()
+        .filter_map(|plan| plan.estimated_bytes)
+        .sum();
+    println!(
+        "Plan: {} tables, about {} rows and {} bytes",
+        plans.len(),
+        estimated_rows,
+        estimated_bytes
+    );
+
+    for plan in plans {
+        println!("{} table {}", plan.category, plan.table);
+        println!("  query: {}", plan.query.replace('\n', "\n    "));
+        println!(
+            "  estimated rows: {}, estimated bytes: {}",
+            get_estimate(plan.estimated_rows),
+            get_estimate(plan.estimated_bytes)
+        );
+        if let Some(rows) = plan.counted_rows {
+            println!("  counted rows: {}", rows);
+        }
+        if plan.actions.is_empty() {
+            println!("  target: nothing, neither output files nor a target DB are configured");
+        }
+        for action in &plan.actions {
+            println!("  target: {}", action);
+        }
+    }
+}
+
+fn get_estimate(value: Option<u64>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "unknown".to_string(),
+    }
+}
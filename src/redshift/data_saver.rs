@@ -2,18 +2,23 @@ use crate::{
     config::{ Config, DbConfig },
     custom_error::{ CustomError, CustomResult },
     logger::LoggerTrait,
+    summary::RunSummary,
     traits::{ DataSaverTrait, InsertQueries },
 };
 
 pub struct DataSaver<'config> {
     pub config: &'config Config,
+    pub summary: &'config RunSummary,
 }
 
 impl<'config> LoggerTrait for DataSaver<'config> {}
 
 impl<'config> DataSaverTrait for DataSaver<'config> {
     fn save(&self, data: &InsertQueries) -> CustomResult<()> {
-        self.save_to_files(data, &self.config.target_path.path)?;
+        if self.config.target_path.write_files {
+            self.save_to_files(data, &self.config.target_path.path)?;
+            self.summary.record_file_bytes(data);
+        }
 
         Ok(())
     }
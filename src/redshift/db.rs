@@ -1,24 +1,38 @@
 use sqlx::Pool;
 use sqlx::postgres::Postgres;
 
-use crate::config::DbConfig;
+use crate::config::{ DbConfig, RetryConfig };
 use crate::custom_error::{ CustomResult, CustomError };
+use crate::retry::{ is_transient_sqlx_error, with_retry_async };
 
-pub async fn get_connections_pool(db_config: &DbConfig) -> CustomResult<Pool<Postgres>> {
+pub async fn get_connections_pool(
+    db_config: &DbConfig,
+    retry_config: &RetryConfig
+) -> CustomResult<Pool<Postgres>> {
     let logger = crate::logger::Logger::new();
     let url = get_url(db_config);
-    let pool = Pool::<Postgres>::connect(&url).await;
 
-    match pool {
-        Ok(pool) => {
-            logger.warn("Created connection Pool for DB");
-            Ok(pool)
-        }
-        Err(err) => {
-            logger.error(format!("Can't create connection Pool: {:#?}", err).as_str());
-            Err(CustomError::DbConnection)
+    with_retry_async(retry_config, "Connecting to Redshift", |_| async {
+        let pool = Pool::<Postgres>::connect(&url).await;
+
+        match pool {
+            Ok(pool) => {
+                logger.warn("Created connection Pool for DB");
+                Ok(pool)
+            }
+            Err(err) => {
+                logger.error(format!("Can't create connection Pool: {:#?}", err).as_str());
+                if is_transient_sqlx_error(&err) {
+                    Err(CustomError::from(err))
+                } else {
+                    Err(CustomError::DbConnection {
+                        database: db_config.database.clone(),
+                        source: Box::new(err),
+                    })
+                }
+            }
         }
-    }
+    }).await
 }
 
 fn get_url(db_config: &DbConfig) -> String {
@@ -1,9 +1,18 @@
-use crate::{ custom_error::CustomResult, logger::LoggerTrait, traits::InsertQueries };
+use crate::{
+    checkpoint::CheckpointStore,
+    config::SampleRoot,
+    custom_error::CustomResult,
+    logger::LoggerTrait,
+    summary::RunSummary,
+    traits::InsertQueries,
+};
 
 use super::redshift_tables_query_generator::RedshiftTablesQueryGenerator;
 
 pub struct InsertQueryGenerator<'config> {
     pub config: &'config crate::config::Config,
+    pub checkpoint: &'config CheckpointStore,
+    pub summary: &'config RunSummary,
 }
 
 impl<'config> LoggerTrait for InsertQueryGenerator<'config> {}
@@ -12,15 +21,25 @@ impl<'config> InsertQueryGenerator<'config> {
     pub async fn generate(&self) -> CustomResult<InsertQueries> {
         let logger = self.get_logger();
         logger.info("Generating insert statement for redshift");
+        let sample = &self.config.sample;
+        let has_tables = !self.config.tables.redshift_tables.is_empty();
+        if has_tables && sample.enabled && sample.root != SampleRoot::Subjects {
+            logger.warn("Redshift tables only follow a subject sample, they keep the whole scope");
+        }
 
-        let redshift_tables_generator = RedshiftTablesQueryGenerator { config: &self.config };
+        let redshift_tables_generator = RedshiftTablesQueryGenerator {
+            config: self.config,
+            checkpoint: self.checkpoint,
+            summary: self.summary,
+        };
         let redshift_tables_sql = redshift_tables_generator.generate().await?;
 
         logger.info("Generated insert statement for redsfhit");
         let result = InsertQueries {
-            batch_tables: None,
-            double_staged_tables: None,
-            triple_staged_tables: None,
+            purge_tables: vec![],
+            batch_tables: vec![],
+            double_staged_tables: vec![],
+            triple_staged_tables: vec![],
             redshift_tables: redshift_tables_sql,
         };
         Ok(result)
@@ -4,3 +4,4 @@ mod db;
 mod redshift_table_query_provider;
 mod redshift_tables_query_generator;
 mod traits;
+pub mod planner;
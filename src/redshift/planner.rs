@@ -0,0 +1,121 @@
+use sqlx::{ Pool, Postgres, Row };
+
+use crate::{
+    cancellation::check_cancelled,
+    config::Config,
+    custom_error::{ CustomError, CustomResult },
+    logger::LoggerTrait,
+    plan::{ get_target_actions, TablePlan },
+    retry::with_retry_async,
+    traits::REDSHIFT_TABLES,
+};
+
+use super::{ db::get_connections_pool, redshift_table_query_provider::RedshiftTableQueryProvider };
+
+// Builds the Redshift select queries and explains them, no rows are fetched
+pub struct Planner<'config> {
+    pub config: &'config Config,
+    // Also runs COUNT(*) over each select
+    pub count_rows: bool,
+}
+
+impl<'config> LoggerTrait for Planner<'config> {}
+impl<'config> Planner<'config> {
+    pub async fn plan(&self) -> CustomResult<Vec<TablePlan>> {
+        let mut plans: Vec<TablePlan> = vec![];
+        if self.config.tables.redshift_tables.is_empty() {
+            return Ok(plans);
+        }
+
+        let mut pool = get_connections_pool(&self.config.redshift_db, &self.config.retry).await?;
+        let provider = RedshiftTableQueryProvider { config: self.config };
+        for table in &self.config.tables.redshift_tables {
+            check_cancelled()?;
+            let query = provider.get_select_query(&mut pool, table, None)?;
+            let query = query.trim_end_matches(';').to_string();
+
+            let operation = format!("Planning table {}", table);
+            let (estimated_rows, estimated_bytes) = with_retry_async(
+                &self.config.retry,
+                &operation,
+                |_| self.explain(&pool, table, &query)
+            ).await?;
+            let counted_rows = if self.count_rows {
+                let operation = format!("Counting rows of table {}", table);
+                Some(
+                    with_retry_async(&self.config.retry, &operation, |_| {
+                        self.count(&pool, table, &query)
+                    }).await?
+                )
+            } else {
+                None
+            };
+
+            plans.push(TablePlan {
+                category: REDSHIFT_TABLES.to_string(),
+                table: table.clone(),
+                query,
+                estimated_rows,
+                estimated_bytes,
+                counted_rows,
+                actions: get_target_actions(
+                    self.config,
+                    REDSHIFT_TABLES,
+                    self.config.insert.get_conflict_mode(table)
+                ),
+            });
+        }
+
+        Ok(plans)
+    }
+
+    // Reads rows= and width= from the top plan node, e.g. "XN Seq Scan on audit
+    // (cost=0.00..1.00 rows=100 width=64)"
+    async fn explain(
+        &self,
+        pool: &Pool<Postgres>,
+        table: &str,
+        select_query: &str
+    ) -> CustomResult<(Option<u64>, Option<u64>)> {
+        let query = format!("EXPLAIN {}", select_query);
+        let rows = sqlx
+            ::query(&query)
+            .fetch_all(pool).await
+            .map_err(|err| CustomError::from(err).with_table(table).with_sql(&query))?;
+
+        let top_node = rows.first().and_then(|row| row.try_get::<String, _>(0).ok());
+        let Some(top_node) = top_node else {
+            return Ok((None, None));
+        };
+        let estimated_rows = get_plan_value(&top_node, "rows=");
+        let width = get_plan_value(&top_node, "width=");
+
+        Ok((estimated_rows, estimated_rows.zip(width).map(|(rows, width)| rows * width)))
+    }
+
+    async fn count(
+        &self,
+        pool: &Pool<Postgres>,
+        table: &str,
+        select_query: &str
+    ) -> CustomResult<u64> {
+        let query = format!("SELECT COUNT(*) FROM (\n{}\n) AS scoped", select_query);
+
+        sqlx
+            ::query(&query)
+            .fetch_one(pool).await
+            .and_then(|row| row.try_get::<i64, _>(0))
+            .map(|count| count.max(0) as u64)
+            .map_err(|err| CustomError::from(err).with_table(table).with_sql(&query))
+    }
+}
+
+fn get_plan_value(plan_node: &str, name: &str) -> Option<u64> {
+    let start = plan_node.find(name)? + name.len();
+    let digits: String = plan_node[start..]
+        .chars()
+        .take_while(|character| character.is_ascii_digit())
+        .collect();
+
+    digits.parse().ok()
+}
@@ -1,5 +1,9 @@
 use sqlx::{ Pool, Postgres };
-use crate::{ config::Config, custom_error::CustomResult, logger::LoggerTrait };
+use crate::{
+    config::{ Config, ConflictMode, SampleRoot },
+    custom_error::{ CustomError, CustomResult },
+    logger::LoggerTrait,
+};
 
 use std::collections::HashMap;
 
@@ -9,7 +13,11 @@ pub struct RedshiftTableQueryProvider<'config> {
     pub config: &'config Config,
 }
 
-impl<'config> TableQueryGenerator for RedshiftTableQueryProvider<'config> {}
+impl<'config> TableQueryGenerator for RedshiftTableQueryProvider<'config> {
+    fn get_config(&self) -> &Config {
+        self.config
+    }
+}
 impl<'config> LoggerTrait for RedshiftTableQueryProvider<'config> {}
 impl<'config> RedshiftTableQueryProvider<'config> {
     pub fn get_select_query(
@@ -45,9 +53,7 @@ impl<'config> RedshiftTableQueryProvider<'config> {
             query.push_str(format!(" AND job_id={}", job_id).as_str());
         }
 
-        if let Some(limit) = self.config.business.limit {
-            query.push_str(format!(" LIMIT {}", limit).as_str());
-        }
+        query.push_str(self.get_subject_filter().as_str());
 
         Ok(query)
     }
@@ -67,17 +73,27 @@ impl<'config> RedshiftTableQueryProvider<'config> {
             query.push_str(format!(" AND job_id={}", job_id).as_str());
         }
 
-        if let Some(limit) = self.config.business.limit {
-            query.push_str(format!(" LIMIT {}", limit).as_str());
-        }
+        query.push_str(self.get_subject_filter().as_str());
 
         Ok(query)
     }
 
+    // Redshift rows hang off the source subjects, so the sample and the limit both keep
+    // the subjects picked from the source instead of cutting each table on its own
+    fn get_subject_filter(&self) -> String {
+        let condition = self.config.sample
+            .get_condition(SampleRoot::Subjects, "subject_id")
+            .or_else(|| self.config.business.get_limit_condition("subject_id"));
+
+        condition.map(|condition| format!(" AND {}", condition)).unwrap_or_default()
+    }
+
     pub fn generate_insert_query(
         &self,
-        data: &Vec<HashMap<String, String>>,
-        table: &String
+        data: &Vec<HashMap<String, Option<String>>>,
+        table: &String,
+        conflict_mode: ConflictMode,
+        key_columns: &[String]
     ) -> CustomResult<String> {
         let logger = self.get_logger();
         logger.info(format!("Generating insert statements for table: {}", table).as_str());
@@ -97,8 +113,8 @@ impl<'config> RedshiftTableQueryProvider<'config> {
             }
             let mut values_as_str = String::new();
             for (index, column) in columns.iter().enumerate() {
-                let value = row.get(column.as_str()).unwrap();
-                values_as_str.push_str(value);
+                let value = row.get(column.as_str()).and_then(Option::as_deref);
+                values_as_str.push_str(get_sql_literal(value).as_str());
 
                 if index < columns.len() - 1 {
                     values_as_str.push_str(", ");
@@ -108,24 +124,194 @@ impl<'config> RedshiftTableQueryProvider<'config> {
             values_as_strings.push(values_as_str);
         }
 
-        columns = columns
-            .iter()
-            .map(|column| format!("`{}`", column))
-            .collect();
+        // Redshift has no ON CONFLICT, rows with a key already in the target are deleted first
+        match conflict_mode {
+            ConflictMode::Fail => {}
+            ConflictMode::Ignore => {
+                return Err(CustomError::UnsupportedConflictMode {
+                    table: table.clone(),
+                    mode: conflict_mode,
+                });
+            }
+            ConflictMode::Upsert | ConflictMode::Replace if key_columns.is_empty() => {
+                return Err(CustomError::MissingPrimaryKey { table: table.clone() });
+            }
+            ConflictMode::Upsert | ConflictMode::Replace => {
+                if !columns.is_empty() {
+                    let delete_query = get_delete_by_keys_query(data, table, key_columns)?;
+                    result.push_str(delete_query.as_str());
+                    result.push('\n');
+                }
+            }
+        }
 
         let insert_query = format!(
             "INSERT INTO\n{} ({})\nVALUES\n({});",
             table,
-            columns.join(", "),
+            columns
+                .iter()
+                .map(|column| quote_column(column))
+                .collect::<Vec<String>>()
+                .join(", "),
             values_as_strings.join("), \n(")
         );
 
-        if columns.len() > 0 {
+        if !columns.is_empty() {
             result.push_str(insert_query.as_str());
-            result.push_str("\n");
+            result.push('\n');
         }
         logger.info(format!("Generated insert statements for table: {}", table).as_str());
 
         Ok(result)
     }
 }
+
+fn get_delete_by_keys_query(
+    data: &[HashMap<String, Option<String>>],
+    table: &String,
+    key_columns: &[String]
+) -> CustomResult<String> {
+    let mut predicates: Vec<String> = vec![];
+    for row in data {
+        let mut conditions: Vec<String> = vec![];
+        for column in key_columns {
+            let column_name = quote_column(column);
+            match row.get(column) {
+                Some(None) => conditions.push(format!("{} IS NULL", column_name)),
+                Some(Some(value)) => {
+                    let literal = get_sql_literal(Some(value));
+                    conditions.push(format!("{} = {}", column_name, literal));
+                }
+                None => {
+                    return Err(CustomError::MissingPrimaryKey { table: table.clone() });
+                }
+            }
+        }
+        predicates.push(format!("({})", conditions.join(" AND ")));
+    }
+
+    Ok(format!("DELETE FROM {}\nWHERE\n{};", table, predicates.join("\nOR ")))
+}
+
+fn quote_column(column: &str) -> String {
+    format!("\"{}\"", column.replace('"', "\"\""))
+}
+
+// Every value goes in as a quoted literal, Redshift casts it to the column type.
+// Backslashes are escapes in Redshift literals, so they are doubled with the quotes
+fn get_sql_literal(value: Option<&str>) -> String {
+    match value {
+        None => "NULL".to_string(),
+        Some(value) => format!("'{}'", value.replace('\\', "\\\\").replace('\'', "''")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::config::get_test_config;
+
+    use super::{ get_delete_by_keys_query, get_sql_literal, quote_column };
+    use super::RedshiftTableQueryProvider;
+
+    fn get_row(values: &[(&str, Option<&str>)]) -> HashMap<String, Option<String>> {
+        values
+            .iter()
+            .map(|(column, value)| (column.to_string(), value.map(str::to_string)))
+            .collect()
+    }
+
+    #[test]
+    fn literals_are_quoted_and_escaped() {
+        assert_eq!(get_sql_literal(None), "NULL");
+        assert_eq!(get_sql_literal(Some("42")), "'42'");
+        assert_eq!(get_sql_literal(Some("2024-01-31")), "'2024-01-31'");
+        assert_eq!(get_sql_literal(Some("it's")), "'it''s'");
+        assert_eq!(
+            get_sql_literal(Some("a\\'); DROP TABLE x; --")),
+            "'a\\\\''); DROP TABLE x; --'"
+        );
+        // The text NULL is a value, not SQL NULL
+        assert_eq!(get_sql_literal(Some("NULL")), "'NULL'");
+    }
+
+    #[test]
+    fn columns_are_double_quoted() {
+        assert_eq!(quote_column("subject_id"), "\"subject_id\"");
+        assert_eq!(quote_column("odd\"name"), "\"odd\"\"name\"");
+    }
+
+    #[test]
+    fn delete_matches_every_key_of_every_row() {
+        let data = vec![
+            get_row(&[("id", Some("1")), ("code", Some("a'b")), ("value", Some("x"))]),
+            get_row(&[("id", Some("2")), ("code", None), ("value", Some("y"))])
+        ];
+        let keys = vec!["id".to_string(), "code".to_string()];
+
+        let query = get_delete_by_keys_query(&data, &"audit".to_string(), &keys).unwrap();
+
+        assert_eq!(
+            query,
+            "DELETE FROM audit\nWHERE\n(\"id\" = '1' AND \"code\" = 'a''b')\n\
+             OR (\"id\" = '2' AND \"code\" IS NULL);"
+        );
+    }
+
+    #[test]
+    fn text_null_key_is_compared_as_a_value() {
+        let data = vec![get_row(&[("code", Some("NULL"))])];
+        let keys = vec!["code".to_string()];
+
+        let query = get_delete_by_keys_query(&data, &"audit".to_string(), &keys).unwrap();
+
+        assert_eq!(query, "DELETE FROM audit\nWHERE\n(\"code\" = 'NULL');");
+    }
+
+    #[test]
+    fn missing_key_column_is_an_error() {
+        let data = vec![get_row(&[("value", Some("x"))])];
+        let keys = vec!["id".to_string()];
+
+        assert!(get_delete_by_keys_query(&data, &"audit".to_string(), &keys).is_err());
+    }
+
+    #[test]
+    fn limit_keeps_the_subjects_picked_from_the_source() {
+        let mut config = get_test_config();
+        config.business.limit = Some(2);
+        config.business.subjects = Some(vec!["7".to_string(), "9".to_string()]);
+        let provider = RedshiftTableQueryProvider { config: &config };
+
+        assert_eq!(provider.get_subject_filter(), " AND subject_id IN (7, 9)");
+    }
+
+    #[test]
+    fn limit_without_subjects_matches_no_rows() {
+        let mut config = get_test_config();
+        config.business.limit = Some(2);
+        config.business.subjects = Some(vec![]);
+        let provider = RedshiftTableQueryProvider { config: &config };
+
+        assert_eq!(provider.get_subject_filter(), " AND subject_id IN (NULL)");
+    }
+
+    #[test]
+    fn sampled_subjects_take_precedence() {
+        let mut config = get_test_config();
+        config.sample.roots = Some(vec!["3".to_string()]);
+        config.business.subjects = Some(vec!["7".to_string()]);
+        let provider = RedshiftTableQueryProvider { config: &config };
+
+        assert_eq!(provider.get_subject_filter(), " AND subject_id IN (3)");
+    }
+
+    #[test]
+    fn unlimited_scope_adds_no_filter() {
+        let config = get_test_config();
+        let provider = RedshiftTableQueryProvider { config: &config };
+
+        assert_eq!(provider.get_subject_filter(), "");
+    }
+}
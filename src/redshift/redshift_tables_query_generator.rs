@@ -1,34 +1,102 @@
+use std::time::Instant;
+
+use sqlx::{ Pool, Postgres };
+
+use crate::cancellation::check_cancelled;
 use crate::logger::LoggerTrait;
-use crate::{ config::Config, custom_error::CustomResult };
+use crate::checkpoint::CheckpointStore;
+use crate::retry::with_retry_async;
+use crate::summary::{ RunSummary, TableStatus };
+use crate::traits::{ TableQuery, REDSHIFT_TABLES };
+use crate::{ config::{ Config, ConflictMode }, custom_error::CustomResult };
 
 use super::{ db::get_connections_pool, redshift_table_query_provider::RedshiftTableQueryProvider };
 use super::traits::TableQueryGenerator;
 
 pub struct RedshiftTablesQueryGenerator<'config> {
     pub config: &'config Config,
+    pub checkpoint: &'config CheckpointStore,
+    pub summary: &'config RunSummary,
 }
 
 impl<'config> LoggerTrait for RedshiftTablesQueryGenerator<'config> {}
 impl<'config> RedshiftTablesQueryGenerator<'config> {
-    pub async fn generate(&self) -> CustomResult<Option<String>> {
-        let logger = self.get_logger();
-        let mut result = String::new();
-        let mut pool = get_connections_pool(&self.config.redshift_db).await?;
-        let provider = RedshiftTableQueryProvider { config: &self.config };
+    pub async fn generate(&self) -> CustomResult<Vec<TableQuery>> {
+        let mut result: Vec<TableQuery> = vec![];
+        let mut pool = get_connections_pool(&self.config.redshift_db, &self.config.retry).await?;
+        let provider = RedshiftTableQueryProvider { config: self.config };
         for table in &self.config.tables.redshift_tables {
-            let mut select_query = provider.get_select_query(&mut pool, table, None)?;
-            select_query.push_str(";");
-            logger.info(format!("select query:\n\n {}\n\n", select_query).as_str());
-            let data = provider.get_data(&mut pool, table, &select_query).await?;
-            let insert_query = provider.generate_insert_query(&data, &table)?;
-            logger.info(format!("insert query:\n\n {}\n\n", insert_query).as_str());
-            result.push_str(insert_query.as_str());
-        }
+            let conflict_mode = self.config.insert.get_conflict_mode(table);
+            let extracted = self.checkpoint.get_extracted(REDSHIFT_TABLES, table, conflict_mode);
+            if let Some(table_query) = extracted {
+                self.summary.record(REDSHIFT_TABLES, table, TableStatus::Extracted);
+                self.summary.record_checkpoint_rows(REDSHIFT_TABLES, table, table_query.rows);
+                result.push(table_query);
+                continue;
+            }
 
-        if result.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(result))
+            let started = Instant::now();
+            let table_query = match check_cancelled() {
+                Ok(_) => self.extract_table(&mut pool, &provider, table, conflict_mode).await,
+                Err(err) => Err(err),
+            };
+            self.summary.record_extract_time(REDSHIFT_TABLES, table, started.elapsed());
+            let table_query = self.summary.settle_extract(
+                self.config,
+                REDSHIFT_TABLES,
+                table,
+                table_query
+            )?;
+            if let Some(table_query) = table_query {
+                self.summary.record_rows(REDSHIFT_TABLES, table, table_query.rows);
+                self.checkpoint.mark_extracted(REDSHIFT_TABLES, &table_query)?;
+                result.push(table_query);
+            }
         }
+
+        Ok(result)
+    }
+
+    async fn extract_table(
+        &self,
+        pool: &mut Pool<Postgres>,
+        provider: &RedshiftTableQueryProvider<'_>,
+        table: &String,
+        conflict_mode: ConflictMode
+    ) -> CustomResult<TableQuery> {
+        let logger = self.get_logger();
+        let mut select_query = provider.get_select_query(pool, table, None)?;
+        select_query.push(';');
+        logger.info(format!("select query:\n\n {}\n\n", select_query).as_str());
+        self.summary.record_query(REDSHIFT_TABLES, table, &select_query);
+        let timeout_ms = self.config.timeout.get_statement_timeout(table);
+        let operation = format!("Extracting table {}", table);
+        let data = with_retry_async(&self.config.retry, &operation, |_| {
+            provider.get_data(pool, table, &select_query, timeout_ms)
+        }).await?;
+        let key_columns = match conflict_mode {
+            ConflictMode::Upsert | ConflictMode::Replace => {
+                let operation = format!("Reading primary key of {}", table);
+                with_retry_async(&self.config.retry, &operation, |_| {
+                    provider.get_primary_key_columns(pool, table)
+                }).await?
+            }
+            ConflictMode::Fail | ConflictMode::Ignore => vec![],
+        };
+        let insert_query = provider.generate_insert_query(
+            &data,
+            table,
+            conflict_mode,
+            &key_columns
+        )?;
+        logger.info(format!("insert query:\n\n {}\n\n", insert_query).as_str());
+
+        Ok(TableQuery {
+            table: table.clone(),
+            query: insert_query,
+            rows: data.len(),
+            conflict_mode,
+            data: None,
+        })
     }
 }
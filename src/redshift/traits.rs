@@ -1,100 +1,131 @@
-use std::collections::HashMap;
+use std::{ collections::HashMap, time::Instant };
 
 use sqlx::postgres::{ types::Oid, PgRow };
 use sqlx::types::chrono::{ DateTime, NaiveDateTime, Utc };
 use sqlx::{ Pool, Postgres, Row };
-use sqlx::Column;
+use sqlx::{ Column, TypeInfo };
 
+use crate::config::Config;
 use crate::custom_error::{ CustomError, CustomResult };
+use crate::metrics;
+use crate::traits::decode_or_null;
 
 pub trait TableQueryGenerator {
+    fn get_config(&self) -> &Config;
+
     async fn get_data(
         &self,
         pool: &Pool<Postgres>,
-        _table: &String,
-        query: &String
-    ) -> CustomResult<Vec<HashMap<String, String>>> {
-        let data = sqlx
-            ::query(query.as_str())
+        table: &str,
+        query: &str,
+        timeout_ms: Option<u64>
+    ) -> CustomResult<Vec<HashMap<String, Option<String>>>> {
+        // statement_timeout is a session setting, so the query runs on one pinned connection
+        let mut connection = pool.acquire().await.map_err(CustomError::from)?;
+        if let Some(timeout_ms) = timeout_ms {
+            let set_timeout = format!("SET statement_timeout TO {};", timeout_ms);
+            sqlx
+                ::query(&set_timeout)
+                .execute(&mut *connection).await
+                .map_err(CustomError::from)?;
+        }
+
+        let started = Instant::now();
+        let data = sqlx::query(query).fetch_all(&mut *connection).await;
+        metrics::observe_query("select", started.elapsed());
+        // The connection goes back to the pool, it must not keep this table's limit
+        let reset = match timeout_ms {
+            Some(_) => sqlx::query("RESET statement_timeout;").execute(&mut *connection).await,
+            None => Ok(Default::default()),
+        };
+        let data = data.map_err(|err| CustomError::from(err).with_table(table).with_sql(query))?;
+        reset.map_err(CustomError::from)?;
+
+        data.iter()
+            .enumerate()
+            .map(|(row_index, row)| self.pg_row_to_hashmap(table, row_index, row))
+            .collect()
+    }
+
+    async fn get_primary_key_columns(
+        &self,
+        pool: &Pool<Postgres>,
+        table: &str
+    ) -> CustomResult<Vec<String>> {
+        let query =
+            r#"
+            SELECT
+                kcu.column_name
+            FROM
+                information_schema.table_constraints tc
+                JOIN information_schema.key_column_usage kcu
+                    ON tc.constraint_name = kcu.constraint_name
+                    AND tc.table_schema = kcu.table_schema
+                    AND tc.table_name = kcu.table_name
+            WHERE
+                tc.constraint_type = 'PRIMARY KEY' AND tc.table_name = $1
+            ORDER BY
+                kcu.ordinal_position
+            "#;
+
+        let rows = sqlx
+            ::query(query)
+            .bind(table)
             .fetch_all(pool).await
-            .map_err(|e| CustomError::DbQueryExecution(e.to_string()))?;
+            .map_err(CustomError::from)?;
 
-        let records = data
-            .into_iter()
+        rows.iter()
             .map(|row| {
-                let record = self.pg_row_to_hashmap(&row);
-                record
+                row.try_get::<String, _>(0).map_err(CustomError::from)
             })
-            .collect::<Vec<HashMap<String, String>>>();
-
-        Ok(records)
+            .collect()
     }
 
-    fn pg_row_to_hashmap(&self, row: &PgRow) -> HashMap<String, String> {
+    fn pg_row_to_hashmap(
+        &self,
+        table: &str,
+        row_index: usize,
+        row: &PgRow
+    ) -> CustomResult<HashMap<String, Option<String>>> {
+        let lenient = self.get_config().extract.lenient_decoding;
         let mut hashmap = HashMap::new();
         for (i, column) in row.columns().iter().enumerate() {
-            let value = match column.type_info().oid().unwrap() {
-                Oid(16) => {
-                    let value = row.try_get::<Option<bool>, _>(i).unwrap();
-                    match value {
-                        Some(val) => val.to_string(),
-                        None => "NULL".to_string(),
-                    }
-                }
-                Oid(20) => {
-                    let value = row.try_get::<Option<i64>, _>(i).unwrap();
-                    match value {
-                        Some(val) => val.to_string(),
-                        None => "NULL".to_string(),
-                    }
+            let value = self.pg_value_to_string(row, i).map_err(|source| {
+                CustomError::DbValueDecoding {
+                    table: table.to_string(),
+                    column: column.name().to_string(),
+                    row: row_index + 1,
+                    sql_type: column.type_info().name().to_string(),
+                    source: Box::new(source),
                 }
-                Oid(21) => {
-                    let value = row.try_get::<Option<i16>, _>(i).unwrap();
-                    match value {
-                        Some(val) => val.to_string(),
-                        None => "NULL".to_string(),
-                    }
-                }
-                Oid(23) => {
-                    let value = row.try_get::<Option<i32>, _>(i).unwrap();
-                    match value {
-                        Some(val) => val.to_string(),
-                        None => "NULL".to_string(),
-                    }
-                }
-                Oid(700) => {
-                    let value = row.try_get::<Option<f32>, _>(i).unwrap();
-                    match value {
-                        Some(val) => val.to_string(),
-                        None => "NULL".to_string(),
-                    }
-                }
-                Oid(701) => {
-                    let value = row.try_get::<Option<f64>, _>(i).unwrap();
-                    match value {
-                        Some(val) => val.to_string(),
-                        None => "NULL".to_string(),
-                    }
-                }
-                Oid(1114) => {
-                    let timestamp = row.try_get::<Option<NaiveDateTime>, _>(i).unwrap();
-                    match timestamp {
-                        Some(timestamp) => timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
-                        None => "NULL".to_string(),
-                    }
-                }
-                Oid(1184) => {
-                    let timestamp = row.try_get::<Option<DateTime<Utc>>, _>(i).unwrap();
-
-                    match timestamp {
-                        Some(timestamp) => timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
-                        None => "NULL".to_string(),
-                    }
-                }
-                _ => row.try_get::<String, _>(i).unwrap().to_string(),
-            };
+            });
+            let value = decode_or_null(lenient, value, None)?;
             hashmap.insert(column.name().to_string(), value);
         }
-        hashmap
+
+        Ok(hashmap)
+    }
+
+    // Types without a known OID are read as text, None is SQL NULL
+    fn pg_value_to_string(&self, row: &PgRow, i: usize) -> Result<Option<String>, sqlx::Error> {
+        let value = match row.columns()[i].type_info().oid() {
+            Some(Oid(16)) => row.try_get::<Option<bool>, _>(i)?.map(|val| val.to_string()),
+            Some(Oid(20)) => row.try_get::<Option<i64>, _>(i)?.map(|val| val.to_string()),
+            Some(Oid(21)) => row.try_get::<Option<i16>, _>(i)?.map(|val| val.to_string()),
+            Some(Oid(23)) => row.try_get::<Option<i32>, _>(i)?.map(|val| val.to_string()),
+            Some(Oid(700)) => row.try_get::<Option<f32>, _>(i)?.map(|val| val.to_string()),
+            Some(Oid(701)) => row.try_get::<Option<f64>, _>(i)?.map(|val| val.to_string()),
+            Some(Oid(1114)) =>
+                row
+                    .try_get::<Option<NaiveDateTime>, _>(i)?
+                    .map(|timestamp| timestamp.format("%Y-%m-%d %H:%M:%S").to_string()),
+            Some(Oid(1184)) =>
+                row
+                    .try_get::<Option<DateTime<Utc>>, _>(i)?
+                    .map(|timestamp| timestamp.format("%Y-%m-%d %H:%M:%S").to_string()),
+            _ => row.try_get::<Option<String>, _>(i)?,
+        };
+
+        Ok(value)
     }
 }
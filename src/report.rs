@@ -0,0 +1,206 @@
+use std::fs;
+
+use serde_json::{ json, Value };
+use sqlx::types::chrono::Local;
+
+use crate::{
+    checkpoint::get_scope,
+    config::Config,
+    custom_error::{ CustomError, CustomResult },
+    files::write_bytes_atomically,
+    summary::{ RunSummary, TableStatus },
+};
+
+// Writes run_report.json to the output folder and prints it when configured,
+// a report that can't be written doesn't change the outcome of the run
+pub fn report(config: &Config, summary: &RunSummary, result: &CustomResult<()>) {
+    let logger = crate::logger::Logger::new();
+    let report = get_report(config, summary, result);
+    let content = match serde_json::to_string_pretty(&report) {
+        Ok(content) => content,
+        Err(err) => {
+            logger.error(format!("Can't serialize run report: {}", err).as_str());
+            return;
+        }
+    };
+
+    if config.report.print {
+        println!("{}", content);
+    }
+    if config.report.write_file {
+        let folder_path = &config.target_path.path;
+        let file_path = format!("{}/run_report.json", folder_path);
+        let written = fs
+            ::create_dir_all(folder_path)
+            .map_err(|source| CustomError::FolderCreationError {
+                path: folder_path.clone(),
+                source,
+            })
+            .and_then(|_| write_bytes_atomically(&file_path, content.as_bytes()));
+        if let Err(err) = written {
+            logger.error(format!("Can't write run report: {}", err.report()).as_str());
+        }
+    }
+}
+
+fn get_report(config: &Config, summary: &RunSummary, result: &CustomResult<()>) -> Value {
+    let started_at = summary.get_started_at();
+    let finished_at = Local::now();
+    let (status, error) = match result {
+        Ok(_) if summary.has_failures() => ("failed", None),
+        Ok(_) => ("succeeded", None),
+        Err(CustomError::Cancelled) => ("cancelled", None),
+        Err(err) => ("failed", Some(err.report())),
+    };
+    let target_db = config.target_db.as_ref().map(|target_db| {
+        json!({
+            "host": target_db.host,
+            "port": target_db.port,
+            "database": target_db.database,
+        })
+    });
+
+    let tables: Vec<Value> = summary
+        .get_outcomes()
+        .iter()
+        .map(|outcome| {
+            let (status, skip_reason, error) = match &outcome.status {
+                TableStatus::Pending => ("pending", None, None),
+                TableStatus::Extracted => ("extracted", None, None),
+                TableStatus::Loaded => ("loaded", None, None),
+                TableStatus::Failed(error) => ("failed", None, Some(error)),
+                TableStatus::Skipped(reason) => ("skipped", Some(reason), None),
+            };
+            json!({
+                "category": outcome.category,
+                "table": outcome.table,
+                "status": status,
+                "skip_reason": skip_reason,
+                "error": error,
+                "select_query": outcome.select_query,
+                "rows": outcome.rows,
+                "bytes_written": outcome.bytes_written,
+                "extract_ms": outcome.extract_time.map(|elapsed| elapsed.as_millis() as u64),
+                "load_ms": outcome.load_time.map(|elapsed| elapsed.as_millis() as u64),
+                "warnings": outcome.warnings,
+            })
+        })
+        .collect();
+    let row_counts: Vec<Value> = summary
+        .get_row_counts()
+        .iter()
+        .map(|check| {
+            json!({
+                "category": check.category,
+                "table": check.table,
+                "extracted": check.extracted,
+                "source_rows": check.source_rows,
+                "target_rows": check.target_rows,
+                "match": check.is_match(),
+            })
+        })
+        .collect();
+    let checksums: Vec<Value> = summary
+        .get_checksums()
+        .iter()
+        .map(|check| {
+            json!({
+                "category": check.category,
+                "table": check.table,
+                "match": check.is_match(),
+                "differing_rows": check.differing_rows,
+                "differing_keys": check.differing_keys,
+            })
+        })
+        .collect();
+    let references: Vec<Value> = summary
+        .get_references()
+        .iter()
+        .map(|check| {
+            json!({
+                "category": check.category,
+                "table": check.table,
+                "column": check.column,
+                "parent_table": check.parent_table,
+                "parent_column": check.parent_column,
+                "orphans": check.orphans,
+                "orphan_values": check.orphan_values,
+            })
+        })
+        .collect();
+    let schema: Vec<Value> = summary
+        .get_schema_differences()
+        .iter()
+        .map(|difference| {
+            json!({
+                "category": difference.category,
+                "table": difference.table,
+                "column": difference.column,
+                "kind": difference.kind,
+                "source": difference.source,
+                "target": difference.target,
+                "blocking": difference.blocking,
+            })
+        })
+        .collect();
+
+    json!({
+        "status": status,
+        "error": error,
+        "started_at": started_at.to_rfc3339(),
+        "finished_at": finished_at.to_rfc3339(),
+        "duration_ms": (finished_at - started_at).num_milliseconds(),
+        "scope": get_scope(config),
+        "target": {
+            "path": config.target_path.path,
+            "db": target_db,
+        },
+        "tables": tables,
+        "row_counts": row_counts,
+        "checksums": checksums,
+        "references": references,
+        "schema": schema,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::config::get_test_config;
+    use crate::custom_error::CustomError;
+    use crate::summary::{ RunSummary, TableStatus };
+
+    use super::get_report;
+
+    #[test]
+    fn status_follows_the_run_result_and_table_failures() {
+        let config = get_test_config();
+        let summary = RunSummary::new();
+        summary.record("batch", "cb_batch_runs", TableStatus::Loaded);
+
+        assert_eq!(get_report(&config, &summary, &Ok(()))["status"], json!("succeeded"));
+        let cancelled = get_report(&config, &summary, &Err(CustomError::Cancelled));
+        assert_eq!(cancelled["status"], json!("cancelled"));
+        assert_eq!(cancelled["error"], json!(null));
+
+        summary.record("batch", "cb_items", TableStatus::Failed("broken".to_string()));
+        assert_eq!(get_report(&config, &summary, &Ok(()))["status"], json!("failed"));
+    }
+
+    #[test]
+    fn tables_keep_their_status_and_reason() {
+        let config = get_test_config();
+        let summary = RunSummary::new();
+        summary.record("batch", "cb_items", TableStatus::Skipped("timed out".to_string()));
+        summary.record_rows("batch", "cb_items", 3);
+
+        let report = get_report(&config, &summary, &Ok(()));
+
+        assert_eq!(report["tables"][0]["table"], json!("cb_items"));
+        assert_eq!(report["tables"][0]["status"], json!("skipped"));
+        assert_eq!(report["tables"][0]["skip_reason"], json!("timed out"));
+        assert_eq!(report["tables"][0]["rows"], json!(3));
+        assert_eq!(report["scope"]["business"]["study_id"], json!(1));
+    }
+}
@@ -0,0 +1,161 @@
+use std::{ future::Future, time::Duration };
+
+use mysql::DriverError;
+use rand::Rng;
+
+use crate::{
+    cancellation::check_cancelled,
+    config::RetryConfig,
+    custom_error::CustomResult,
+    metrics,
+};
+
+// Lock wait timeout, deadlock, too many connections and lost network connections
+const TRANSIENT_MYSQL_CODES: [u16; 9] = [1205, 1213, 1040, 1158, 1159, 1160, 1161, 2006, 2013];
+// Serialization failure and deadlock
+const TRANSIENT_POSTGRES_CODES: [&str; 2] = ["40001", "40P01"];
+
+pub fn is_transient_mysql_error(err: &mysql::Error) -> bool {
+    match err {
+        mysql::Error::MySqlError(mysql_error) => TRANSIENT_MYSQL_CODES.contains(&mysql_error.code),
+        mysql::Error::IoError(_) => true,
+        mysql::Error::DriverError(driver_error) =>
+            matches!(
+                driver_error,
+                DriverError::ConnectTimeout | DriverError::CouldNotConnect(_) | DriverError::Timeout
+            ),
+        _ => false,
+    }
+}
+
+pub fn is_transient_sqlx_error(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Database(database_error) => {
+            let code = database_error.code();
+            let is_transient_code = code.is_some_and(|code|
+                TRANSIENT_POSTGRES_CODES.contains(&code.as_ref())
+            );
+            // Redshift reports its serialization conflicts as internal errors (XX000)
+            let is_serialization_conflict = database_error
+                .message()
+                .contains("Serializable isolation violation");
+
+            is_transient_code || is_serialization_conflict
+        }
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut => true,
+        _ => false,
+    }
+}
+
+// Runs the operation until it succeeds, fails with a non transient error, runs out of attempts
+// or the run is cancelled.
+// The closure gets the 1-based attempt number, so it can reconnect before retrying.
+pub fn with_retry<T, F>(config: &RetryConfig, operation: &str, mut f: F) -> CustomResult<T>
+    where F: FnMut(u32) -> CustomResult<T>
+{
+    let mut attempt = 1;
+    loop {
+        match f(attempt) {
+            Err(err) if err.is_transient() && attempt < config.max_attempts => {
+                // A cancelled run doesn't wait for another attempt
+                check_cancelled()?;
+                let delay = get_delay(config, attempt);
+                log_retry(config, operation, attempt, &err.report(), delay);
+                metrics::add_retry();
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            result => {
+                return result;
+            }
+        }
+    }
+}
+
+pub async fn with_retry_async<T, F, Fut>(
+    config: &RetryConfig,
+    operation: &str,
+    mut f: F
+) -> CustomResult<T>
+    where F: FnMut(u32) -> Fut, Fut: Future<Output = CustomResult<T>>
+{
+    let mut attempt = 1;
+    loop {
+        match f(attempt).await {
+            Err(err) if err.is_transient() && attempt < config.max_attempts => {
+                // A cancelled run doesn't wait for another attempt
+                check_cancelled()?;
+                let delay = get_delay(config, attempt);
+                log_retry(config, operation, attempt, &err.report(), delay);
+                metrics::add_retry();
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => {
+                return result;
+            }
+        }
+    }
+}
+
+// Exponential backoff capped at max_delay_ms, with the upper half of the delay randomized
+fn get_delay(config: &RetryConfig, attempt: u32) -> Duration {
+    let exponent = (attempt - 1).min(16);
+    let delay = config.initial_delay_ms.saturating_mul(1 << exponent).min(config.max_delay_ms);
+    let jitter = rand::thread_rng().gen_range(0..=delay / 2);
+
+    Duration::from_millis(delay - delay / 2 + jitter)
+}
+
+fn log_retry(config: &RetryConfig, operation: &str, attempt: u32, error: &str, delay: Duration) {
+    let logger = crate::logger::Logger::new();
+    logger.warn(
+        format!(
+            "{} failed on attempt {}/{}: {}. Retrying in {} ms",
+            operation,
+            attempt,
+            config.max_attempts,
+            error,
+            delay.as_millis()
+        ).as_str()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::config::RetryConfig;
+
+    use super::get_delay;
+
+    fn get_config() -> RetryConfig {
+        RetryConfig { max_attempts: 10, initial_delay_ms: 100, max_delay_ms: 1000 }
+    }
+
+    #[test]
+    fn delay_doubles_with_jitter_in_the_upper_half() {
+        let config = get_config();
+        for (attempt, delay) in [(1, 100), (2, 200), (3, 400), (4, 800)] {
+            let result = get_delay(&config, attempt);
+
+            assert!(result >= Duration::from_millis(delay / 2), "attempt {}", attempt);
+            assert!(result <= Duration::from_millis(delay), "attempt {}", attempt);
+        }
+    }
+
+    #[test]
+    fn delay_is_capped() {
+        let config = get_config();
+        for attempt in [5, 20, 100, u32::MAX] {
+            assert!(get_delay(&config, attempt) <= Duration::from_millis(config.max_delay_ms));
+        }
+    }
+
+    #[test]
+    fn zero_delay_stays_zero() {
+        let config = RetryConfig { max_attempts: 3, initial_delay_ms: 0, max_delay_ms: 1000 };
+
+        assert_eq!(get_delay(&config, 3), Duration::ZERO);
+    }
+}
@@ -0,0 +1,482 @@
+use std::{ sync::Mutex, time::Duration };
+
+use sqlx::types::chrono::{ DateTime, Local };
+
+use crate::{
+    config::Config,
+    custom_error::{ CustomError, CustomResult },
+    logger::LoggerTrait,
+    metrics,
+    timeout::apply_timeout_policy,
+    traits::{
+        InsertQueries,
+        BATCH_TABLES,
+        DOUBLE_STAGED_TABLES,
+        REDSHIFT_TABLES,
+        TRIPLE_STAGED_TABLES,
+    },
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TableStatus {
+    // Extract started and hasn't settled yet
+    Pending,
+    Extracted,
+    Loaded,
+    Failed(String),
+    Skipped(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct TableOutcome {
+    pub category: String,
+    pub table: String,
+    pub status: TableStatus,
+    pub select_query: Option<String>,
+    pub rows: Option<usize>,
+    // SQL text in the output files plus bulk load files
+    pub bytes_written: u64,
+    pub extract_time: Option<Duration>,
+    // Summed over the statements of the table
+    pub load_time: Option<Duration>,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RowCountCheck {
+    pub category: String,
+    pub table: String,
+    pub extracted: usize,
+    pub source_rows: u64,
+    pub target_rows: u64,
+}
+
+impl RowCountCheck {
+    pub fn is_match(&self) -> bool {
+        self.source_rows == (self.extracted as u64) && self.target_rows == self.source_rows
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChecksumCheck {
+    pub category: String,
+    pub table: String,
+    pub source_checksum: u64,
+    pub target_checksum: u64,
+    // Set when keys are reported, differing_keys then holds the first of them
+    pub differing_rows: Option<usize>,
+    pub differing_keys: Vec<String>,
+}
+
+impl ChecksumCheck {
+    pub fn is_match(&self) -> bool {
+        self.source_checksum == self.target_checksum
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReferenceCheck {
+    pub category: String,
+    pub table: String,
+    pub column: String,
+    pub parent_table: String,
+    pub parent_column: String,
+    pub orphans: usize,
+    // The first orphan values, rendered as SQL literals
+    pub orphan_values: Vec<String>,
+}
+
+// One column, or a whole table, that differs between source and target
+#[derive(Debug, Clone)]
+pub struct SchemaDifference {
+    pub category: String,
+    pub table: String,
+    // None when the whole table is missing from the target
+    pub column: Option<String>,
+    pub kind: String,
+    pub source: String,
+    pub target: String,
+    // The schema policy fails the run on it
+    pub blocking: bool,
+}
+
+// Last known status of every table touched by the run, in the order they were first seen
+#[derive(Debug)]
+pub struct RunSummary {
+    started_at: DateTime<Local>,
+    outcomes: Mutex<Vec<TableOutcome>>,
+    row_counts: Mutex<Vec<RowCountCheck>>,
+    checksums: Mutex<Vec<ChecksumCheck>>,
+    references: Mutex<Vec<ReferenceCheck>>,
+    schema: Mutex<Vec<SchemaDifference>>,
+}
+
+impl LoggerTrait for RunSummary {}
+impl RunSummary {
+    pub fn new() -> Self {
+        Self {
+            started_at: Local::now(),
+            outcomes: Mutex::new(vec![]),
+            row_counts: Mutex::new(vec![]),
+            checksums: Mutex::new(vec![]),
+            references: Mutex::new(vec![]),
+            schema: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn record(&self, category: &str, table: &str, status: TableStatus) {
+        self.update(category, table, |outcome| {
+            outcome.status = status;
+        });
+    }
+
+    pub fn record_query(&self, category: &str, table: &str, select_query: &str) {
+        self.update(category, table, |outcome| {
+            outcome.select_query = Some(select_query.to_string());
+        });
+    }
+
+    pub fn record_rows(&self, category: &str, table: &str, rows: usize) {
+        self.update(category, table, |outcome| {
+            outcome.rows = Some(rows);
+        });
+        metrics::add_rows(category, table, rows as u64);
+    }
+
+    // Rows of a table the checkpoint already holds, this run didn't copy them
+    pub fn record_checkpoint_rows(&self, category: &str, table: &str, rows: usize) {
+        self.update(category, table, |outcome| {
+            outcome.rows = Some(rows);
+        });
+    }
+
+    pub fn record_extract_time(&self, category: &str, table: &str, elapsed: Duration) {
+        self.update(category, table, |outcome| {
+            outcome.extract_time = Some(elapsed);
+        });
+        metrics::observe_duration("extract", elapsed);
+    }
+
+    pub fn record_load_time(&self, category: &str, table: &str, elapsed: Duration) {
+        self.update(category, table, |outcome| {
+            outcome.load_time = Some(outcome.load_time.unwrap_or_default() + elapsed);
+        });
+        metrics::observe_duration("load", elapsed);
+    }
+
+    pub fn record_bytes(&self, category: &str, table: &str, bytes: u64) {
+        self.update(category, table, |outcome| {
+            outcome.bytes_written += bytes;
+        });
+        metrics::add_bytes(category, table, bytes);
+    }
+
+    // Purge statements aren't tied to an extracted table and aren't counted
+    pub fn record_file_bytes(&self, data: &InsertQueries) {
+        let categories = [
+            (BATCH_TABLES, &data.batch_tables),
+            (DOUBLE_STAGED_TABLES, &data.double_staged_tables),
+            (TRIPLE_STAGED_TABLES, &data.triple_staged_tables),
+            (REDSHIFT_TABLES, &data.redshift_tables),
+        ];
+        for (category, queries) in categories {
+            for table_query in queries {
+                self.record_bytes(category, &table_query.table, table_query.query.len() as u64);
+            }
+        }
+    }
+
+    pub fn record_warning(&self, category: &str, table: &str, message: &str) {
+        self.update(category, table, |outcome| {
+            outcome.warnings.push(message.to_string());
+        });
+    }
+
+    fn update<F: FnOnce(&mut TableOutcome)>(&self, category: &str, table: &str, update: F) {
+        let mut outcomes = self.outcomes.lock().unwrap();
+        let position = outcomes
+            .iter()
+            .position(|outcome| outcome.category == category && outcome.table == table);
+        let position = position.unwrap_or_else(|| {
+            outcomes.push(TableOutcome {
+                category: category.to_string(),
+                table: table.to_string(),
+                status: TableStatus::Pending,
+                select_query: None,
+                rows: None,
+                bytes_written: 0,
+                extract_time: None,
+                load_time: None,
+                warnings: vec![],
+            });
+            outcomes.len() - 1
+        });
+
+        update(&mut outcomes[position]);
+    }
+
+    pub fn record_row_count(&self, check: RowCountCheck) {
+        self.row_counts.lock().unwrap().push(check);
+    }
+
+    pub fn record_checksum(&self, check: ChecksumCheck) {
+        self.checksums.lock().unwrap().push(check);
+    }
+
+    pub fn record_reference(&self, check: ReferenceCheck) {
+        self.references.lock().unwrap().push(check);
+    }
+
+    pub fn record_schema_difference(&self, difference: SchemaDifference) {
+        self.schema.lock().unwrap().push(difference);
+    }
+
+    pub fn is_loaded(&self, category: &str, table: &str) -> bool {
+        self.outcomes
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|outcome| {
+                outcome.category == category &&
+                    outcome.table == table &&
+                    outcome.status == TableStatus::Loaded
+            })
+    }
+
+    pub fn record_failure(&self, category: &str, table: &str, err: &CustomError) {
+        let status = match err {
+            CustomError::Cancelled => TableStatus::Skipped("run cancelled".to_string()),
+            err => {
+                metrics::add_failure(category);
+                TableStatus::Failed(err.report())
+            }
+        };
+        self.record(category, table, status);
+    }
+
+    // Records how the extract of a table ended. A failed table is dropped from the output
+    // when continue_on_error is set, otherwise its error ends the run.
+    pub fn settle_extract<T>(
+        &self,
+        config: &Config,
+        category: &str,
+        table: &str,
+        result: CustomResult<T>
+    ) -> CustomResult<Option<T>> {
+        match apply_timeout_policy(config, category, table, result) {
+            Ok(Some(value)) => {
+                self.record(category, table, TableStatus::Extracted);
+                Ok(Some(value))
+            }
+            Ok(None) => {
+                self.record(category, table, TableStatus::Skipped("extract timed out".to_string()));
+                Ok(None)
+            }
+            Err(err) => {
+                self.record_failure(category, table, &err);
+                self.continue_after(config, category, table, err).map(|_| None)
+            }
+        }
+    }
+
+    // Swallows a per-table error when continue_on_error is set, cancellation always stops
+    pub fn continue_after(
+        &self,
+        config: &Config,
+        category: &str,
+        table: &str,
+        err: CustomError
+    ) -> CustomResult<()> {
+        if !config.errors.continue_on_error || matches!(err, CustomError::Cancelled) {
+            return Err(err);
+        }
+
+        let message = format!("Skipping {} table {}: {}", category, table, err.report());
+        self.get_logger().error(message.as_str());
+        Ok(())
+    }
+
+    pub fn has_failures(&self) -> bool {
+        self.outcomes
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|outcome| matches!(outcome.status, TableStatus::Failed(_)))
+    }
+
+    pub fn print(&self) {
+        let outcomes = self.outcomes.lock().unwrap();
+        if outcomes.is_empty() {
+            return;
+        }
+
+        let count = |f: fn(&TableStatus) -> bool| {
+            outcomes
+                .iter()
+                .filter(|outcome| f(&outcome.status))
+                .count()
+        };
+        eprintln!(
+            "Run summary: {} succeeded, {} failed, {} skipped",
+            count(|status| matches!(status, TableStatus::Extracted | TableStatus::Loaded)),
+            count(|status| matches!(status, TableStatus::Failed(_))),
+            count(|status| matches!(status, TableStatus::Skipped(_)))
+        );
+        for outcome in outcomes.iter() {
+            let status = match &outcome.status {
+                TableStatus::Pending => "pending".to_string(),
+                TableStatus::Extracted => "extracted".to_string(),
+                TableStatus::Loaded => "loaded".to_string(),
+                TableStatus::Failed(reason) =>
+                    format!("FAILED\n    {}", reason.replace('\n', "\n    ")),
+                TableStatus::Skipped(reason) => format!("skipped ({})", reason),
+            };
+            eprintln!("  {} table {}: {}", outcome.category, outcome.table, status);
+        }
+        drop(outcomes);
+
+        self.print_row_counts();
+        self.print_checksums();
+        self.print_references();
+        self.print_schema();
+    }
+
+    pub fn get_started_at(&self) -> DateTime<Local> {
+        self.started_at
+    }
+
+    pub fn get_outcomes(&self) -> Vec<TableOutcome> {
+        self.outcomes.lock().unwrap().clone()
+    }
+
+    pub fn get_row_counts(&self) -> Vec<RowCountCheck> {
+        self.row_counts.lock().unwrap().clone()
+    }
+
+    pub fn get_checksums(&self) -> Vec<ChecksumCheck> {
+        self.checksums.lock().unwrap().clone()
+    }
+
+    pub fn get_references(&self) -> Vec<ReferenceCheck> {
+        self.references.lock().unwrap().clone()
+    }
+
+    pub fn get_schema_differences(&self) -> Vec<SchemaDifference> {
+        self.schema.lock().unwrap().clone()
+    }
+
+    fn print_row_counts(&self) {
+        let row_counts = self.row_counts.lock().unwrap();
+        if row_counts.is_empty() {
+            return;
+        }
+
+        let mismatches: Vec<&RowCountCheck> = row_counts
+            .iter()
+            .filter(|check| !check.is_match())
+            .collect();
+        eprintln!(
+            "Row count verification: {} tables checked, {} mismatched",
+            row_counts.len(),
+            mismatches.len()
+        );
+        for check in mismatches {
+            eprintln!(
+                "  {} table {}: {} extracted, {} in source, {} in target",
+                check.category,
+                check.table,
+                check.extracted,
+                check.source_rows,
+                check.target_rows
+            );
+        }
+    }
+
+    fn print_checksums(&self) {
+        let checksums = self.checksums.lock().unwrap();
+        if checksums.is_empty() {
+            return;
+        }
+
+        let mismatches: Vec<&ChecksumCheck> = checksums
+            .iter()
+            .filter(|check| !check.is_match())
+            .collect();
+        eprintln!(
+            "Checksum verification: {} tables checked, {} differ",
+            checksums.len(),
+            mismatches.len()
+        );
+        for check in mismatches {
+            let keys = check.differing_keys.join("; ");
+            let rows = match check.differing_rows {
+                Some(count) if count > check.differing_keys.len() =>
+                    format!(", {} rows differ, first keys: {}", count, keys),
+                Some(count) => format!(", {} rows differ, keys: {}", count, keys),
+                None => String::new(),
+            };
+            eprintln!("  {} table {}: checksums differ{}", check.category, check.table, rows);
+        }
+    }
+
+    fn print_references(&self) {
+        let references = self.references.lock().unwrap();
+        if references.is_empty() {
+            return;
+        }
+
+        let orphaned: Vec<&ReferenceCheck> = references
+            .iter()
+            .filter(|check| check.orphans > 0)
+            .collect();
+        eprintln!(
+            "Reference check: {} references checked, {} with orphan rows",
+            references.len(),
+            orphaned.len()
+        );
+        for check in orphaned {
+            eprintln!(
+                "  {} table {}: {} values of {} missing from {}.{}: {}",
+                check.category,
+                check.table,
+                check.orphans,
+                check.column,
+                check.parent_table,
+                check.parent_column,
+                check.orphan_values.join(", ")
+            );
+        }
+    }
+
+    fn print_schema(&self) {
+        let schema = self.schema.lock().unwrap();
+        if schema.is_empty() {
+            return;
+        }
+
+        let blocking = schema
+            .iter()
+            .filter(|difference| difference.blocking)
+            .count();
+        eprintln!(
+            "Schema check: {} differences with the target, {} blocking",
+            schema.len(),
+            blocking
+        );
+        for difference in schema.iter() {
+            let location = match &difference.column {
+                Some(column) => format!("{}.{}", difference.table, column),
+                None => difference.table.clone(),
+            };
+            eprintln!(
+                "  {} {} {}: {} in source, {} in target{}",
+                difference.category,
+                location,
+                difference.kind,
+                difference.source,
+                difference.target,
+                if difference.blocking { ", blocking" } else { "" }
+            );
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use crate::{
+    config::{ Config, TimeoutPolicy },
+    custom_error::{ CustomError, CustomResult },
+};
+
+// ER_QUERY_TIMEOUT, raised when MAX_EXECUTION_TIME is exceeded
+const MYSQL_QUERY_TIMEOUT_CODE: u16 = 3024;
+// query_canceled, raised when statement_timeout is exceeded
+const POSTGRES_QUERY_CANCELED_CODE: &str = "57014";
+
+pub fn is_timeout_mysql_error(err: &mysql::Error) -> bool {
+    match err {
+        mysql::Error::MySqlError(mysql_error) => mysql_error.code == MYSQL_QUERY_TIMEOUT_CODE,
+        _ => false,
+    }
+}
+
+pub fn is_timeout_sqlx_error(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Database(database_error) =>
+            database_error.code().is_some_and(|code| code == POSTGRES_QUERY_CANCELED_CODE),
+        _ => false,
+    }
+}
+
+// Applies the timeout policy to an extract result, a skipped table comes back as None
+pub fn apply_timeout_policy<T>(
+    config: &Config,
+    category: &str,
+    table: &str,
+    result: CustomResult<T>
+) -> CustomResult<Option<T>> {
+    let logger = crate::logger::Logger::new();
+
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(err @ CustomError::DbQueryTimeout(_)) => {
+            let err = err.with_table(table);
+            let message = format!("Extract of {} table failed: {}", category, err.report());
+            logger.error(message.as_str());
+            match config.timeout.on_timeout {
+                TimeoutPolicy::Stop => Err(err),
+                TimeoutPolicy::Skip => {
+                    logger.warn(format!("Skipping {} table {}", category, table).as_str());
+                    Ok(None)
+                }
+            }
+        }
+        Err(err) => Err(err),
+    }
+}
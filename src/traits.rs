@@ -1,16 +1,81 @@
-use std::{ fs::{ self, File }, io::Write };
+use std::fs;
 
-use crate::{ config::DbConfig, custom_error::{ CustomError, CustomResult } };
+use crate::{
+    config::{ ConflictMode, DbConfig },
+    custom_error::{ CustomError, CustomResult },
+    files::write_bytes_atomically,
+    mysql::traits::TableData,
+};
+
+// Category names used in logs, checkpoints and reports
+pub const PURGE_TABLES: &str = "purge";
+pub const BATCH_TABLES: &str = "batch";
+pub const DOUBLE_STAGED_TABLES: &str = "double staged";
+pub const TRIPLE_STAGED_TABLES: &str = "triple staged";
+pub const REDSHIFT_TABLES: &str = "redshift";
+
+#[derive(Debug, Clone)]
+pub struct TableQuery {
+    pub table: String,
+    pub query: String,
+    pub rows: usize,
+    pub conflict_mode: ConflictMode,
+    // Extracted rows, only kept when the load method needs them
+    pub data: Option<TableData>,
+}
 
 pub struct InsertQueries {
-    pub batch_tables: Option<String>,
-    pub double_staged_tables: Option<String>,
-    pub triple_staged_tables: Option<String>,
-    pub redshift_tables: Option<String>,
+    pub purge_tables: Vec<TableQuery>,
+    pub batch_tables: Vec<TableQuery>,
+    pub double_staged_tables: Vec<TableQuery>,
+    pub triple_staged_tables: Vec<TableQuery>,
+    pub redshift_tables: Vec<TableQuery>,
+}
+
+impl InsertQueries {
+    // Drops extracted rows that were only kept for checks
+    pub fn release_table_data(&mut self) {
+        let categories = [
+            &mut self.batch_tables,
+            &mut self.double_staged_tables,
+            &mut self.triple_staged_tables,
+        ];
+        for table_query in categories.into_iter().flatten() {
+            table_query.data = None;
+        }
+    }
+}
+
+impl TableQuery {
+    // Nothing to load, the table had no rows in scope
+    pub fn is_empty(&self) -> bool {
+        self.query.is_empty() &&
+            self.data.as_ref().is_none_or(|data| data.rows.is_empty())
+    }
+}
+
+// Lenient decoding turns a value that can't be decoded into NULL, other errors pass through
+pub fn decode_or_null<T>(lenient: bool, result: CustomResult<T>, null: T) -> CustomResult<T> {
+    match result {
+        Err(err @ CustomError::DbValueDecoding { .. }) if lenient => {
+            let logger = crate::logger::Logger::new();
+            logger.warn(format!("{}, writing NULL", err.report()).as_str());
+            Ok(null)
+        }
+        result => result,
+    }
+}
+
+pub fn join_queries(queries: &[TableQuery]) -> String {
+    queries
+        .iter()
+        .map(|table_query| table_query.query.as_str())
+        .collect()
 }
 
 pub trait TablesInsertQueryGeneratorTrait {
-    fn generate(&self) -> CustomResult<Option<String>>;
+    fn generate(&self) -> CustomResult<Vec<TableQuery>>;
+    fn generate_purge(&self) -> CustomResult<Vec<TableQuery>>;
 }
 
 pub trait TechnologyInsertGeneratorTrait {
@@ -22,46 +87,36 @@ pub trait DataSaverTrait {
     fn save_to_db(&self, data: &InsertQueries, config: &DbConfig) -> CustomResult<()>;
     fn save_to_files(&self, data: &InsertQueries, folder_path: &String) -> CustomResult<()> {
         self.create_folder(folder_path)?;
-        if let Some(batch_tables) = &data.batch_tables {
+        if !data.purge_tables.is_empty() {
+            let file_path = format!("{}/purge_tables.sql", folder_path);
+            self.save_to_file(&join_queries(&data.purge_tables), &file_path)?;
+        }
+
+        if !data.batch_tables.is_empty() {
             let file_path = format!("{}/batch_tables.sql", folder_path);
-            self.save_to_file(batch_tables, &file_path)?;
+            self.save_to_file(&join_queries(&data.batch_tables), &file_path)?;
         }
 
-        if let Some(double_staged_tables) = &data.double_staged_tables {
+        if !data.double_staged_tables.is_empty() {
             let file_path = format!("{}/double_staged_tables.sql", folder_path);
-            self.save_to_file(double_staged_tables, &file_path)?;
+            self.save_to_file(&join_queries(&data.double_staged_tables), &file_path)?;
         }
 
-        if let Some(triple_staged_tables) = &data.triple_staged_tables {
+        if !data.triple_staged_tables.is_empty() {
             let file_path = format!("{}/triple_staged_tables.sql", folder_path);
-            self.save_to_file(triple_staged_tables, &file_path)?;
+            self.save_to_file(&join_queries(&data.triple_staged_tables), &file_path)?;
         }
 
-        if let Some(redshift_tables) = &data.redshift_tables {
+        if !data.redshift_tables.is_empty() {
             let file_path = format!("{}/redshift_tables.sql", folder_path);
-            self.save_to_file(redshift_tables, &file_path)?;
+            self.save_to_file(&join_queries(&data.redshift_tables), &file_path)?;
         }
 
         Ok(())
     }
 
-    fn save_to_file(&self, data: &String, file_path: &String) -> CustomResult<()> {
-        let file_result = File::create(file_path);
-
-        let mut file = match file_result {
-            Ok(file) => file,
-            Err(_) => {
-                return Err(CustomError::FileCreationError);
-            }
-        };
-
-        // Write the data to the file
-        let write_result = file.write_all(data.as_bytes());
-
-        match write_result {
-            Ok(_) => Ok(()),
-            Err(_) => Err(CustomError::FileDataInsertionError),
-        }
+    fn save_to_file(&self, data: &String, file_path: &str) -> CustomResult<()> {
+        write_bytes_atomically(file_path, data.as_bytes())
     }
 
     fn create_folder(&self, folder_path: &String) -> CustomResult<()> {
@@ -75,7 +130,7 @@ pub trait DataSaverTrait {
                     logger.warn("Folder already exists");
                     Ok(())
                 } else {
-                    Err(CustomError::FolderCreationError)
+                    Err(CustomError::FolderCreationError { path: folder_path.clone(), source: err })
                 }
             }
         }
@@ -0,0 +1,71 @@
+use std::{ sync::{ atomic::{ AtomicUsize, Ordering }, Mutex }, thread };
+
+// Runs the job for every item on up to `workers` threads, results keep the order of the items
+pub fn run_ordered<T, R, F>(workers: usize, items: &[T], job: F) -> Vec<R>
+    where T: Sync, R: Send, F: Fn(&T) -> R + Sync
+{
+    let next_index = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+    let workers = workers.clamp(1, items.len().max(1));
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                loop {
+                    let index = next_index.fetch_add(1, Ordering::SeqCst);
+                    let Some(item) = items.get(index) else {
+                        break;
+                    };
+                    let result = job(item);
+                    results.lock().unwrap()[index] = Some(result);
+                }
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.expect("every item is processed by a worker"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ collections::HashSet, sync::Mutex, thread, time::Duration };
+
+    use super::run_ordered;
+
+    #[test]
+    fn results_keep_the_item_order() {
+        let items: Vec<u64> = (0..20).collect();
+
+        // Earlier items take longer, so they finish after the later ones
+        let results = run_ordered(4, &items, |item| {
+            thread::sleep(Duration::from_millis(20 - item));
+            item * 10
+        });
+
+        assert_eq!(results, items.iter().map(|item| item * 10).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn work_is_spread_over_the_workers() {
+        let items: Vec<u64> = (0..8).collect();
+        let threads = Mutex::new(HashSet::new());
+
+        run_ordered(4, &items, |_| {
+            threads.lock().unwrap().insert(thread::current().id());
+            thread::sleep(Duration::from_millis(10));
+        });
+
+        assert!(threads.into_inner().unwrap().len() > 1);
+    }
+
+    #[test]
+    fn no_items_and_zero_workers_are_fine() {
+        assert!(run_ordered(4, &[] as &[u64], |item| *item).is_empty());
+        assert_eq!(run_ordered(0, &[1, 2, 3], |item| item + 1), vec![2, 3, 4]);
+    }
+}